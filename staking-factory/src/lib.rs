@@ -1,28 +1,41 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedSet;
-use near_sdk::json_types::{Base58CryptoHash, U128};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json::json;
 use near_sdk::{
-    env, ext_contract, is_promise_success, log, near_bindgen, sys, AccountId, Balance, CryptoHash,
-    PanicOnDefault, Promise, PromiseOrValue, PublicKey,
+    env, ext_contract, log, near_bindgen, promise_result_as_success, sys, AccountId, Balance,
+    CryptoHash, PanicOnDefault, Promise, PromiseOrValue, PublicKey,
 };
 
 /// The 4 NEAR tokens required for the storage of the staking pool.
 const MIN_ATTACHED_BALANCE: Balance = 4_000_000_000_000_000_000_000_000;
 
 const NEW_METHOD_NAME: &str = "new";
+const GET_VERSION_METHOD_NAME: &str = "get_version";
 const ON_STAKING_POOL_CREATE: &str = "on_staking_pool_create";
 
+/// Prefix of the version the created staking pool must report to be whitelisted.
+const STAKING_POOL_VERSION_PREFIX: &str = "staking-farm:";
+
 /// There is no deposit balance attached.
 const NO_DEPOSIT: Balance = 0;
 
+/// Storage key of the hash of the latest staking contract.
+const LATEST_CODE_KEY: &[u8] = b"latest";
+
 /// Burn fee that whitelisted contracts take.
 const BURN_FEE_FRACTION: Ratio = Ratio {
     numerator: 0,
     denominator: 10,
 };
 
+/// Maximum fraction of the reward that goes to the owner, same as in the staking pool contract.
+const MAX_REWARD_FEE: Ratio = Ratio {
+    numerator: 1,
+    denominator: 2,
+};
+
 pub mod gas {
     use near_sdk::Gas;
 
@@ -33,6 +46,10 @@ pub mod gas {
     /// The base for the execution and the base for staking action to verify the staking key.
     pub const STAKING_POOL_NEW: Gas = Gas(BASE.0 * 2);
 
+    /// The amount of Gas the contract will attach to check the version of the created staking
+    /// pool in the same batch.
+    pub const STAKING_POOL_GET_VERSION: Gas = Gas(10_000_000_000_000);
+
     /// The amount of Gas the contract will attach to the callback to itself.
     /// The base for the execution and the base for whitelist call or cash rollback.
     pub const CALLBACK: Gas = Gas(BASE.0 * 2);
@@ -70,6 +87,11 @@ impl Ratio {
             self.numerator <= self.denominator,
             "The reward fee must be less or equal to 1"
         );
+        assert!(
+            u64::from(self.numerator) * u64::from(MAX_REWARD_FEE.denominator)
+                <= u64::from(MAX_REWARD_FEE.numerator) * u64::from(self.denominator),
+            "ERR_REWARD_FEE_TOO_HIGH"
+        );
     }
 }
 
@@ -84,6 +106,19 @@ pub struct StakingPoolArgs {
     reward_fee_fraction: Ratio,
     /// Burn fee fraction.
     burn_fee_fraction: Ratio,
+    /// Farm tokens authorized from the start.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_farm_tokens: Option<Vec<AccountId>>,
+}
+
+/// Configuration of the factory, everything needed to create a staking pool in one view.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FactoryConfig {
+    pub owner_id: AccountId,
+    pub staking_pool_whitelist_account_id: AccountId,
+    pub min_attached_balance: U128,
+    pub number_of_staking_pools_created: U64,
 }
 
 /// External interface for the callbacks to self.
@@ -135,6 +170,12 @@ impl StakingPoolFactory {
         self.staking_pool_account_ids.len()
     }
 
+    /// Returns whether the given staking pool was created by this factory.
+    pub fn is_created_by_factory(&self, staking_pool_account_id: AccountId) -> bool {
+        self.staking_pool_account_ids
+            .contains(&staking_pool_account_id)
+    }
+
     /// Creates a new staking pool.
     /// - `staking_pool_id` - the prefix of the account ID that will be used to create a new staking
     ///    pool account. It'll be prepended to the staking pool factory account ID separated by dot.
@@ -145,6 +186,7 @@ impl StakingPoolFactory {
     /// - `stake_public_key` - the initial staking key for the staking pool.
     /// - `reward_fee_fraction` - the initial reward fee fraction for the staking pool.
     /// - `burn_fee_fraction` - the burn fee fraction for the staking pool.
+    /// - `initial_farm_tokens` - the tokens that are authorized for farms from the start.
     #[payable]
     pub fn create_staking_pool(
         &mut self,
@@ -153,6 +195,7 @@ impl StakingPoolFactory {
         owner_id: AccountId,
         stake_public_key: PublicKey,
         reward_fee_fraction: Ratio,
+        initial_farm_tokens: Option<Vec<AccountId>>,
     ) {
         assert!(
             env::attached_deposit() >= MIN_ATTACHED_BALANCE,
@@ -178,6 +221,12 @@ impl StakingPoolFactory {
             "The owner account ID is invalid"
         );
         reward_fee_fraction.assert_valid();
+        for token_id in initial_farm_tokens.iter().flatten() {
+            assert!(
+                env::is_valid_account_id(token_id.as_bytes()),
+                "The farm token account ID is invalid"
+            );
+        }
 
         assert!(
             self.is_contract_allowed(&code_hash),
@@ -198,13 +247,18 @@ impl StakingPoolFactory {
                 stake_public_key,
                 reward_fee_fraction,
                 burn_fee_fraction: BURN_FEE_FRACTION,
+                initial_farm_tokens,
             },
         );
     }
 
     /// Callback after a staking pool was created.
-    /// Returns the promise to whitelist the staking pool contract if the pool creation succeeded.
-    /// Otherwise refunds the attached deposit and returns `false`.
+    /// Returns the promise to whitelist the staking pool contract if the pool creation succeeded
+    /// and the pool reports the staking farm version.
+    /// If the creation failed, e.g. the deployed code can't run `new` or `get_version`, the whole
+    /// batch is reverted, so it refunds the attached deposit and returns `false`.
+    /// If the pool reports another version, it's not whitelisted and `false` is returned. The
+    /// deposit can't be refunded then, it's already on the created account.
     #[private]
     pub fn on_staking_pool_create(
         &mut self,
@@ -212,9 +266,20 @@ impl StakingPoolFactory {
         attached_deposit: U128,
         predecessor_account_id: AccountId,
     ) -> PromiseOrValue<bool> {
-        let staking_pool_created = is_promise_success();
+        let version = promise_result_as_success();
+        let staking_pool_created = version.is_some();
 
         if staking_pool_created {
+            let version = near_sdk::serde_json::from_slice::<String>(&version.unwrap_or_default())
+                .unwrap_or_default();
+            if !version.starts_with(STAKING_POOL_VERSION_PREFIX) {
+                log!(
+                    "The staking pool @{} reports unexpected version \"{}\". Not whitelisting",
+                    staking_pool_account_id,
+                    version
+                );
+                return PromiseOrValue::Value(false);
+            }
             log!(
                 "The staking pool @{} was successfully created. Whitelisting...",
                 staking_pool_account_id
@@ -240,8 +305,9 @@ impl StakingPoolFactory {
         }
     }
 
-    /// Returns code at the given hash.
-    pub fn get_code(&self, code_hash: Base58CryptoHash) {
+    /// Returns code at the given hash, or the latest code if the hash is not given.
+    pub fn get_code(&self, code_hash: Option<Base58CryptoHash>) {
+        let code_hash = code_hash.unwrap_or_else(|| self.get_latest_allowed_code_hash());
         assert!(
             self.is_contract_allowed(&code_hash),
             "Contract hash is not allowed"
@@ -261,23 +327,89 @@ impl StakingPoolFactory {
         }
     }
 
+    /// Returns the size in bytes of the code stored at the given hash, without returning it.
+    pub fn get_code_size(&self, code_hash: Base58CryptoHash) -> U64 {
+        let code_hash: CryptoHash = code_hash.into();
+        unsafe {
+            // Load the code into the register, if it exists.
+            assert_eq!(
+                sys::storage_read(code_hash.len() as _, code_hash.as_ptr() as _, 0),
+                1,
+                "Contract doesn't exist"
+            );
+            U64(sys::register_len(0))
+        }
+    }
+
+    /// Returns the account ID of the factory owner.
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Transfer ownership of the factory to the new account. Only owner.
+    pub fn set_owner(&mut self, new_owner_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            env::is_valid_account_id(new_owner_id.as_bytes()),
+            "The owner account ID is invalid"
+        );
+        self.owner_id = new_owner_id;
+    }
+
+    /// Returns the account ID of the staking pool whitelist contract.
+    pub fn get_whitelist_account(&self) -> AccountId {
+        self.staking_pool_whitelist_account_id.clone()
+    }
+
+    /// Returns the configuration of the factory.
+    pub fn get_factory_config(&self) -> FactoryConfig {
+        FactoryConfig {
+            owner_id: self.get_owner(),
+            staking_pool_whitelist_account_id: self.get_whitelist_account(),
+            min_attached_balance: self.get_min_attached_balance(),
+            number_of_staking_pools_created: self.get_number_of_staking_pools_created().into(),
+        }
+    }
+
+    /// Set the staking pool whitelist contract, used for the pools created after. Only owner.
+    pub fn set_whitelist_account(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            env::is_valid_account_id(account_id.as_bytes()),
+            "The staking pool whitelist account ID is invalid"
+        );
+        self.staking_pool_whitelist_account_id = account_id;
+    }
+
+    /// Set the latest version of the staking contract, which pools upgrade to by default.
+    /// The contract must be allowed. Only owner.
+    pub fn set_latest_code(&mut self, code_hash: Base58CryptoHash) {
+        self.assert_owner();
+        assert!(
+            self.is_contract_allowed(&code_hash),
+            "Contract hash is not allowed"
+        );
+        let code_hash: CryptoHash = code_hash.into();
+        env::storage_write(LATEST_CODE_KEY, &code_hash);
+    }
+
+    /// Returns the hash of the latest version of the staking contract.
+    pub fn get_latest_allowed_code_hash(&self) -> Base58CryptoHash {
+        let code_hash = env::storage_read(LATEST_CODE_KEY).expect("ERR_NO_LATEST_CODE");
+        let mut result: CryptoHash = [0; 32];
+        result.copy_from_slice(&code_hash);
+        Base58CryptoHash::from(result)
+    }
+
     /// Allow contract to be deployed. Only owner.
     pub fn allow_contract(&mut self, code_hash: Base58CryptoHash) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "ERR_MUST_BE_OWNER"
-        );
+        self.assert_owner();
         env::storage_write(&Self::code_hash_to_key(&code_hash), &[]);
     }
 
     /// Disallow contract to be deployed. Only owner.
     pub fn disallow_contract(&mut self, code_hash: Base58CryptoHash) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner_id,
-            "ERR_MUST_BE_OWNER"
-        );
+        self.assert_owner();
         env::storage_remove(&Self::code_hash_to_key(&code_hash));
     }
 
@@ -286,6 +418,14 @@ impl StakingPoolFactory {
         env::storage_has_key(&Self::code_hash_to_key(code_hash))
     }
 
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "ERR_MUST_BE_OWNER"
+        );
+    }
+
     /// Map code hash into a storage key.
     fn code_hash_to_key(code_hash: &Base58CryptoHash) -> Vec<u8> {
         format!(
@@ -366,6 +506,16 @@ fn create_contract(
             &NO_DEPOSIT as *const u128 as _,
             gas::STAKING_POOL_NEW.0,
         );
+        // check the version of the deployed code, its failure reverts the whole batch.
+        sys::promise_batch_action_function_call(
+            promise_id,
+            GET_VERSION_METHOD_NAME.len() as _,
+            GET_VERSION_METHOD_NAME.as_ptr() as _,
+            0,
+            b"".as_ptr() as _,
+            &NO_DEPOSIT as *const u128 as _,
+            gas::STAKING_POOL_GET_VERSION.0,
+        );
         // attach callback to the factory.
         let _ = sys::promise_then(
             promise_id,
@@ -465,6 +615,7 @@ mod tests {
         testing_env!(context.clone());
         assert_eq!(contract.get_min_attached_balance().0, MIN_ATTACHED_BALANCE);
         assert_eq!(contract.get_number_of_staking_pools_created(), 0);
+        assert!(!contract.is_created_by_factory(account_pool()));
 
         context.is_view = false;
         context.predecessor_account_id = account_tokens_owner().into();
@@ -481,16 +632,29 @@ mod tests {
                 numerator: 10,
                 denominator: 100,
             },
+            None,
         );
 
         context.predecessor_account_id = account_factory().into();
         context.attached_deposit = ntoy(0);
-        testing_env_with_promise_results(context.clone(), PromiseResult::Successful(vec![]));
-        contract.on_staking_pool_create(account_pool(), ntoy(31).into(), account_tokens_owner());
+        testing_env_with_promise_results(
+            context.clone(),
+            PromiseResult::Successful(b"\"staking-farm:1.2.0\"".to_vec()),
+        );
+        assert!(matches!(
+            contract.on_staking_pool_create(account_pool(), ntoy(31).into(), account_tokens_owner()),
+            PromiseOrValue::Promise(_)
+        ));
 
         context.is_view = true;
         testing_env!(context.clone());
         assert_eq!(contract.get_number_of_staking_pools_created(), 1);
+        assert!(contract.is_created_by_factory(account_pool()));
+        let config = contract.get_factory_config();
+        assert_eq!(config.owner_id, account_near());
+        assert_eq!(config.staking_pool_whitelist_account_id, account_whitelist());
+        assert_eq!(config.min_attached_balance.0, MIN_ATTACHED_BALANCE);
+        assert_eq!(config.number_of_staking_pools_created.0, 1);
     }
 
     #[test]
@@ -529,6 +693,38 @@ mod tests {
                 numerator: 10,
                 denominator: 100,
             },
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REWARD_FEE_TOO_HIGH")]
+    fn test_create_staking_pool_reward_fee_too_high() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        let hash = add_staking_contract(&mut context);
+        contract.allow_contract(hash);
+
+        context.predecessor_account_id = account_tokens_owner().into();
+        context.attached_deposit = MIN_ATTACHED_BALANCE;
+        testing_env!(context.clone());
+        contract.create_staking_pool(
+            staking_pool_id(),
+            hash,
+            account_pool_owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            Ratio {
+                numerator: 60,
+                denominator: 100,
+            },
+            None,
         );
     }
 
@@ -549,6 +745,7 @@ mod tests {
         testing_env!(context.clone());
         assert_eq!(contract.get_min_attached_balance().0, MIN_ATTACHED_BALANCE);
         assert_eq!(contract.get_number_of_staking_pools_created(), 0);
+        assert!(!contract.is_created_by_factory(account_pool()));
 
         context.is_view = false;
         context.predecessor_account_id = account_tokens_owner().into();
@@ -565,6 +762,7 @@ mod tests {
                 numerator: 10,
                 denominator: 100,
             },
+            None,
         );
 
         context.predecessor_account_id = account_factory().into();
@@ -584,6 +782,89 @@ mod tests {
         context.is_view = true;
         testing_env!(context.clone());
         assert_eq!(contract.get_number_of_staking_pools_created(), 0);
+        assert!(!contract.is_created_by_factory(account_pool()));
+    }
+
+    #[test]
+    fn test_create_staking_pool_unexpected_version() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        let hash = add_staking_contract(&mut context);
+
+        contract.allow_contract(hash);
+
+        context.predecessor_account_id = account_tokens_owner().into();
+        context.attached_deposit = ntoy(31);
+        testing_env!(context.clone());
+        contract.create_staking_pool(
+            staking_pool_id(),
+            hash,
+            account_pool_owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            Ratio {
+                numerator: 10,
+                denominator: 100,
+            },
+            None,
+        );
+
+        context.predecessor_account_id = account_factory().into();
+        context.attached_deposit = ntoy(0);
+        testing_env_with_promise_results(
+            context.clone(),
+            PromiseResult::Successful(b"\"other-pool:1.0.0\"".to_vec()),
+        );
+        let res = contract.on_staking_pool_create(
+            account_pool(),
+            ntoy(31).into(),
+            account_tokens_owner(),
+        );
+        match res {
+            PromiseOrValue::Promise(_) => panic!("Unexpected result, should return Value(false)"),
+            PromiseOrValue::Value(value) => assert!(!value),
+        };
+
+        // The account exists, but the pool is not whitelisted.
+        context.is_view = true;
+        testing_env!(context.clone());
+        assert_eq!(contract.get_number_of_staking_pools_created(), 1);
+        assert!(contract.is_created_by_factory(account_pool()));
+    }
+
+    #[test]
+    fn test_get_code_size() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        let hash = add_staking_contract(&mut context);
+        assert_eq!(
+            contract.get_code_size(hash).0,
+            include_bytes!("../../res/staking_farm_release.wasm").len() as u64
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract doesn't exist")]
+    fn test_get_code_size_not_stored() {
+        let context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context);
+
+        let contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        contract.get_code_size([0u8; 32].into());
     }
 
     #[test]
@@ -603,4 +884,80 @@ mod tests {
         contract.disallow_contract(hash);
         assert!(!contract.is_contract_allowed(&hash));
     }
+
+    #[test]
+    #[should_panic(expected = "ERR_MUST_BE_OWNER")]
+    fn test_set_owner() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        assert_eq!(contract.get_owner(), account_near());
+        contract.set_owner(account_tokens_owner());
+        assert_eq!(contract.get_owner(), account_tokens_owner());
+
+        // Previous owner can't manage the factory anymore.
+        let hash = add_staking_contract(&mut context);
+        contract.allow_contract(hash);
+    }
+
+    #[test]
+    fn test_latest_code() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        let hash = add_staking_contract(&mut context);
+        contract.allow_contract(hash);
+        contract.set_latest_code(hash);
+        assert_eq!(contract.get_latest_allowed_code_hash(), hash);
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract hash is not allowed")]
+    fn test_latest_code_not_allowed() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        let hash = add_staking_contract(&mut context);
+        contract.set_latest_code(hash);
+    }
+
+    #[test]
+    fn test_set_whitelist_account() {
+        let context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_near())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        assert_eq!(contract.get_whitelist_account(), account_whitelist());
+        let new_whitelist: AccountId = "whitelist2".parse().unwrap();
+        contract.set_whitelist_account(new_whitelist.clone());
+        assert_eq!(contract.get_whitelist_account(), new_whitelist);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MUST_BE_OWNER")]
+    fn test_set_whitelist_account_not_owner() {
+        let context = VMContextBuilder::new()
+            .current_account_id(account_factory())
+            .predecessor_account_id(account_tokens_owner())
+            .build();
+        testing_env!(context.clone());
+
+        let mut contract = StakingPoolFactory::new(account_near(), account_whitelist());
+        contract.set_whitelist_account("whitelist2".parse().unwrap());
+    }
 }
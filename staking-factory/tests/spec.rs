@@ -1,4 +1,4 @@
-use near_sdk::json_types::Base58CryptoHash;
+use near_sdk::json_types::{Base58CryptoHash, U64};
 use near_sdk::serde_json::{self, json};
 use near_sdk::{AccountId, PublicKey};
 use near_sdk_sim::{
@@ -102,7 +102,8 @@ fn create_staking_pool(
             code_hash,
             user.account_id(),
             STAKING_KEY.parse().unwrap(),
-            fee
+            fee,
+            None
         ),
         deposit = to_yocto(POOL_DEPOSIT)
     )
@@ -200,9 +201,9 @@ fn test_staking_pool_burn() {
 #[test]
 fn test_get_code() {
     let (_root, _foundation, factory, code_hash) = setup_factory();
-    let result: Vec<u8> = view!(factory.get_code(code_hash)).unwrap();
+    let result: Vec<u8> = view!(factory.get_code(Some(code_hash))).unwrap();
     assert_eq!(result, STAKING_FARM_BYTES.to_vec());
-    assert!(view!(factory.get_code([0u8; 32].into()))
+    assert!(view!(factory.get_code(Some([0u8; 32].into())))
         .unwrap_err()
         .to_string()
         .find("Contract hash is not allowed")
@@ -253,5 +254,13 @@ fn test_staking_pool_upgrade_from_1_0_0() {
     ));
     // Check that contract works.
     assert_eq!(get_staking_pool_key(&root), STAKING_KEY.parse().unwrap());
-    assert_eq!(get_version(&root), "staking-farm:1.1.0");
+    assert_eq!(get_version(&root), "staking-farm:1.2.0");
+    let last_upgrade_epoch: Option<U64> = root
+        .view(
+            AccountId::new_unchecked(STAKING_POOL_ACCOUNT_ID.to_string()),
+            "get_last_upgrade_epoch",
+            &[],
+        )
+        .unwrap_json();
+    assert_eq!(last_upgrade_epoch, Some(U64(root.borrow_runtime().cur_block.epoch_height)));
 }
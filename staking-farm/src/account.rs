@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::io;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::{AccountId, Balance, EpochHeight};
@@ -9,7 +10,9 @@ use crate::U256;
 pub type NumStakeShares = Balance;
 
 /// Inner account data of a delegate.
-#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+/// NOTE: New fields must be appended to the end of the struct and handled in `deserialize` to
+/// keep accounts stored by the previous versions readable.
+#[derive(BorshSerialize, Debug, PartialEq)]
 pub struct Account {
     /// The unstaked balance. It represents the amount the account has on this contract that
     /// can either be staked or withdrawn.
@@ -30,6 +33,43 @@ pub struct Account {
     /// Note: It's not persisted in the state, but initialized during internal_get_account.
     #[borsh_skip]
     pub is_burn_account: bool,
+    /// Part of `amounts` attributed to each farm, used for claiming from a single farm.
+    /// Tokens recorded before this field existed are only in `amounts`.
+    pub farm_amounts: HashMap<u64, Balance>,
+    /// Total amount claimed for each token.
+    /// Doesn't keep the account from being deleted, so it's reset if the account is emptied.
+    pub total_claimed: HashMap<AccountId, Balance>,
+}
+
+impl BorshDeserialize for Account {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        let unstaked = BorshDeserialize::deserialize(buf)?;
+        let stake_shares = BorshDeserialize::deserialize(buf)?;
+        let unstaked_available_epoch_height = BorshDeserialize::deserialize(buf)?;
+        let last_farm_reward_per_share = BorshDeserialize::deserialize(buf)?;
+        let amounts = BorshDeserialize::deserialize(buf)?;
+        // Accounts saved by the previous versions end here.
+        let farm_amounts = if buf.is_empty() {
+            HashMap::new()
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let total_claimed = if buf.is_empty() {
+            HashMap::new()
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        Ok(Self {
+            unstaked,
+            stake_shares,
+            unstaked_available_epoch_height,
+            last_farm_reward_per_share,
+            amounts,
+            is_burn_account: false,
+            farm_amounts,
+            total_claimed,
+        })
+    }
 }
 
 impl Default for Account {
@@ -41,6 +81,8 @@ impl Default for Account {
             last_farm_reward_per_share: HashMap::new(),
             amounts: HashMap::new(),
             is_burn_account: false,
+            farm_amounts: HashMap::new(),
+            total_claimed: HashMap::new(),
         }
     }
 }
@@ -0,0 +1,115 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, AccountId, Balance};
+
+use crate::account::NumStakeShares;
+
+/// Standard name of the events emitted by this contract.
+const EVENT_STANDARD: &str = "staking-farm";
+/// Version of the events format.
+const EVENT_VERSION: &str = "1.0.0";
+
+/// Logs the event in the NEP-297 format, so indexers can parse it.
+fn emit_event(event: &str, data: Value) {
+    env::log_str(&format!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+            "event": event,
+            "data": [data],
+        })
+    ));
+}
+
+/// The account deposited `amount` to its unstaked balance.
+pub(crate) fn emit_deposit(account_id: &AccountId, amount: Balance, unstaked: Balance) {
+    emit_event(
+        "deposit",
+        json!({
+            "account_id": account_id,
+            "amount": U128(amount),
+            "unstaked_balance": U128(unstaked),
+        }),
+    );
+}
+
+/// The account staked `amount` from its unstaked balance and received `num_shares`.
+pub(crate) fn emit_stake(account_id: &AccountId, amount: Balance, num_shares: NumStakeShares) {
+    emit_event(
+        "stake",
+        json!({
+            "account_id": account_id,
+            "amount": U128(amount),
+            "stake_shares": U128(num_shares),
+        }),
+    );
+}
+
+/// `send_account_id` received `amount` of `token_id` claimed from `claim_account_id`.
+/// The claim is `delegated` when the owner of the delegator account claims its reward.
+pub(crate) fn emit_farm_claim(
+    claim_account_id: &AccountId,
+    send_account_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+    farm_id: Option<u64>,
+    delegated: bool,
+) {
+    emit_event(
+        "farm_claim",
+        json!({
+            "claim_account_id": claim_account_id,
+            "send_account_id": send_account_id,
+            "token_id": token_id,
+            "amount": U128(amount),
+            "farm_id": farm_id,
+            "delegated": delegated,
+        }),
+    );
+}
+
+/// The transfer of the claimed reward failed and `amount` of `token_id` returned to the account.
+pub(crate) fn emit_farm_claim_refund(
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+    farm_id: Option<u64>,
+) {
+    emit_event(
+        "farm_claim_refund",
+        json!({
+            "account_id": account_id,
+            "token_id": token_id,
+            "amount": U128(amount),
+            "farm_id": farm_id,
+        }),
+    );
+}
+
+/// The contract code was upgraded and the state migrated from `old_version` to `new_version`.
+pub(crate) fn emit_contract_upgraded(old_version: &str, new_version: &str) {
+    emit_event(
+        "contract_upgraded",
+        json!({
+            "old_version": old_version,
+            "new_version": new_version,
+            "epoch_height": env::epoch_height().to_string(),
+        }),
+    );
+}
+
+/// Total staked balance or total number of "stake" shares changed.
+pub(crate) fn emit_price_update(
+    total_staked_balance: Balance,
+    total_stake_shares: NumStakeShares,
+) {
+    emit_event(
+        "price_update",
+        json!({
+            "total_staked_balance": U128(total_staked_balance),
+            "total_stake_shares": U128(total_stake_shares),
+            "epoch_height": env::epoch_height().to_string(),
+        }),
+    );
+}
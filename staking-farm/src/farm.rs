@@ -1,11 +1,18 @@
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::json_types::U64;
 use near_sdk::{assert_one_yocto, is_promise_success, promise_result_as_success, Timestamp};
 
+use crate::events;
 use crate::stake::ext_self;
 use crate::*;
 
 const SESSION_INTERVAL: u64 = 1_000_000_000;
 const DENOMINATOR: u128 = 1_000_000_000_000_000_000_000_000;
+/// New farm can start in the past within this interval, start date is moved to the current block.
+/// Allows to start the farm "now" regardless of the delay between sending and executing.
+const START_DATE_TOLERANCE: u64 = 60 * SESSION_INTERVAL;
+/// Maximum number of times the reward of the front-loaded farm can halve.
+const MAX_CURVE_HALVINGS: u64 = 64;
 
 /// Amount of gas for fungible token transfers.
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
@@ -14,8 +21,87 @@ pub const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(20_000_000_000_000);
 /// Gas for calling `get_owner` method.
 pub const GAS_FOR_GET_OWNER: Gas = Gas(10_000_000_000_000);
 pub const GAS_LEFTOVERS: Gas = Gas(20_000_000_000_000);
+/// Upper bound of gas to distribute the rewards of a single farm to an account.
+/// Covers reading and writing the farm, the reward math and the log. The actual cost is measured
+/// by `test_farm_distribution_gas` in the simulation tests, that fails if it exceeds half of it.
+pub const GAS_PER_FARM_DISTRIBUTION: Gas = Gas(5_000_000_000_000);
 /// Get owner method on external contracts.
 pub const GET_OWNER_METHOD: &str = "get_owner_account_id";
+/// Amount of gas for registering the receiver in the fungible token storage.
+pub const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+/// Amount of gas for `storage_balance_of` and `storage_balance_bounds` views of the token.
+pub const GAS_FOR_STORAGE_VIEW: Gas = Gas(5_000_000_000_000);
+/// Amount of gas for the execution of each callback of `claim_and_register`.
+pub const GAS_FOR_REGISTER_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// Maximum of the get owner gas and the leftovers of the delegated claim together.
+/// Keeps most of the 300 Tgas of the maximum prepaid gas for the claim itself.
+pub const MAX_DELEGATED_CLAIM_GAS: Gas = Gas(100_000_000_000_000);
+/// Maximum number of delegators to claim from in one transaction.
+const MAX_DELEGATED_CLAIMS: usize = 10;
+
+/// Storage management of the farm tokens.
+#[ext_contract(ext_storage_management)]
+pub trait StorageManagement {
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+    fn storage_balance_of(&self, account_id: AccountId);
+    fn storage_balance_bounds(&self);
+}
+
+/// Part of `storage_balance_bounds` of the token used to register the receiver.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+}
+
+/// Gas to transfer the farm token and to resolve the transfer.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct FarmTokenGas {
+    pub transfer_gas: Gas,
+    pub resolve_gas: Gas,
+}
+
+impl Default for FarmTokenGas {
+    fn default() -> Self {
+        Self {
+            transfer_gas: GAS_FOR_FT_TRANSFER,
+            resolve_gas: GAS_FOR_RESOLVE_TRANSFER,
+        }
+    }
+}
+
+impl FarmTokenGas {
+    /// Gas of the callback after registering the receiver, that transfers the reward.
+    fn post_storage_deposit_gas(&self) -> Gas {
+        self.transfer_gas + self.resolve_gas + GAS_FOR_REGISTER_CALLBACK
+    }
+
+    /// Gas of the callback after getting the storage bounds, that registers the receiver.
+    fn post_storage_bounds_gas(&self) -> Gas {
+        GAS_FOR_STORAGE_DEPOSIT + self.post_storage_deposit_gas() + GAS_FOR_REGISTER_CALLBACK
+    }
+
+    /// Gas of the callback after getting the storage balance of the receiver.
+    fn post_storage_balance_gas(&self) -> Gas {
+        GAS_FOR_STORAGE_VIEW + self.post_storage_bounds_gas() + GAS_FOR_REGISTER_CALLBACK
+    }
+}
+
+/// Gas to call `get_owner_account_id` of the delegator and to leave after the delegated claim.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct DelegatedClaimGas {
+    pub get_owner_gas: Gas,
+    pub gas_leftovers: Gas,
+}
+
+impl Default for DelegatedClaimGas {
+    fn default() -> Self {
+        Self {
+            get_owner_gas: GAS_FOR_GET_OWNER,
+            gas_leftovers: GAS_LEFTOVERS,
+        }
+    }
+}
 
 #[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
 pub struct RewardDistribution {
@@ -26,7 +112,63 @@ pub struct RewardDistribution {
     pub reward_round: u64,
 }
 
-#[derive(BorshSerialize, BorshDeserialize)]
+/// How the farm amount is distributed over the farm duration.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RewardCurve {
+    /// The same reward every session.
+    Linear,
+    /// The reward per session halves every `half_life_sessions` sessions.
+    FrontLoaded { half_life_sessions: u64 },
+}
+
+impl Default for RewardCurve {
+    fn default() -> Self {
+        RewardCurve::Linear
+    }
+}
+
+/// Stage of the farm at the given time.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FarmStatus {
+    /// The farm is funded, but the start date hasn't come yet.
+    Pending,
+    /// The farm is distributing the rewards.
+    Running,
+    /// The farm reached the end date or was stopped.
+    Ended,
+}
+
+impl RewardCurve {
+    pub fn assert_valid(&self, num_sessions: u64) {
+        if let RewardCurve::FrontLoaded { half_life_sessions } = self {
+            assert!(*half_life_sessions > 0, "ERR_FARM_CURVE_HALF_LIFE");
+            assert!(
+                num_sessions / half_life_sessions <= MAX_CURVE_HALVINGS,
+                "ERR_FARM_CURVE_HALF_LIFE"
+            );
+        }
+    }
+}
+
+/// Total weight of the first `rounds` sessions of the front-loaded farm with `num_sessions`.
+/// The weight halves every `half_life` sessions, the weight of the last period is 1.
+fn front_loaded_weight(rounds: u64, half_life: u64, num_sessions: u64) -> U256 {
+    let rounds = std::cmp::min(rounds, num_sessions);
+    let last_period = (num_sessions - 1) / half_life;
+    let full_periods = rounds / half_life;
+    // h * (2^P + 2^(P-1) + ... + 2^(P-k+1)) for the `k` full periods.
+    let mut weight = U256::from(half_life)
+        * ((U256::one() << (last_period + 1)) - (U256::one() << (last_period + 1 - full_periods)));
+    if full_periods <= last_period {
+        weight += U256::from(rounds - full_periods * half_life)
+            * (U256::one() << (last_period - full_periods));
+    }
+    weight
+}
+
+#[derive(BorshSerialize)]
 pub struct Farm {
     pub name: String,
     pub token_id: AccountId,
@@ -34,30 +176,173 @@ pub struct Farm {
     pub start_date: Timestamp,
     pub end_date: Timestamp,
     pub last_distribution: RewardDistribution,
+    pub curve: RewardCurve,
+    /// Account that funded the farm, can stop it. Unknown for the farms created before.
+    pub creator: Option<AccountId>,
+    /// Block timestamp when the farm was created. Unknown for the farms created before.
+    pub created_at: Option<Timestamp>,
+    /// Amount the reward schedule is computed from, if it differs from `amount` after reducing
+    /// the running farm. The schedule is scaled down, so the rest of the farm is distributed
+    /// by the same curve until the same `end_date`.
+    pub emission_amount: Option<Balance>,
+    /// Amount recorded to the accounts by this farm. Unknown for the farms created before.
+    pub recorded_amount: Option<Balance>,
+    /// Block timestamp of the last failed transfer of the farm token, claimed from this farm or
+    /// returned as the leftover.
+    pub failed_transfer_at: Option<Timestamp>,
+}
+
+impl BorshDeserialize for Farm {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let name = BorshDeserialize::deserialize(buf)?;
+        let token_id = BorshDeserialize::deserialize(buf)?;
+        let amount = BorshDeserialize::deserialize(buf)?;
+        let start_date = BorshDeserialize::deserialize(buf)?;
+        let end_date = BorshDeserialize::deserialize(buf)?;
+        let last_distribution = BorshDeserialize::deserialize(buf)?;
+        // Farms saved by the previous versions end here.
+        let curve = if buf.is_empty() {
+            RewardCurve::default()
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let creator = if buf.is_empty() {
+            None
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let created_at = if buf.is_empty() {
+            None
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let emission_amount = if buf.is_empty() {
+            None
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let recorded_amount = if buf.is_empty() {
+            None
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        let failed_transfer_at = if buf.is_empty() {
+            None
+        } else {
+            BorshDeserialize::deserialize(buf)?
+        };
+        Ok(Self {
+            name,
+            token_id,
+            amount,
+            start_date,
+            end_date,
+            last_distribution,
+            curve,
+            creator,
+            created_at,
+            emission_amount,
+            recorded_amount,
+            failed_transfer_at,
+        })
+    }
 }
 
 impl Farm {
     pub fn is_active(&self) -> bool {
         self.last_distribution.undistributed > 0
     }
+
+    /// Returns the stage of the farm at the given timestamp.
+    pub fn status(&self, timestamp: Timestamp) -> FarmStatus {
+        if !self.is_active() || timestamp >= self.end_date {
+            FarmStatus::Ended
+        } else if timestamp < self.start_date {
+            FarmStatus::Pending
+        } else {
+            FarmStatus::Running
+        }
+    }
+
+    /// Returns the number of seconds from the given timestamp until the end of the farm.
+    pub fn seconds_remaining(&self, timestamp: Timestamp) -> u64 {
+        if !self.is_active() {
+            return 0;
+        }
+        self.end_date.saturating_sub(timestamp) / SESSION_INTERVAL
+    }
+
+    /// Asserts that the given account is the owner of the pool or the creator of the farm.
+    fn assert_owner_or_creator(&self, account_id: &AccountId) {
+        assert!(
+            account_id == &StakingContract::internal_get_owner_id()
+                || self.creator.as_ref() == Some(account_id),
+            "ERR_MUST_BE_OWNER_OR_CREATOR"
+        );
+    }
+
+    /// Returns the amount the reward schedule is computed from.
+    fn emission_amount(&self) -> Balance {
+        self.emission_amount.unwrap_or(self.amount)
+    }
+
+    /// Returns the reward of each session of the linear farm, the average for other curves.
+    pub fn reward_per_session(&self) -> Balance {
+        if self.end_date <= self.start_date {
+            return 0;
+        }
+        // The farm lasts at least one session, so the result fits into `Balance`.
+        (U256::from(self.emission_amount()) * U256::from(SESSION_INTERVAL)
+            / U256::from(self.end_date - self.start_date))
+        .as_u128()
+    }
+
+    /// Returns the amount distributed by the farm after the given number of sessions from
+    /// the start date, according to the farm's curve.
+    fn distributed_after(&self, rounds: u64) -> Balance {
+        match &self.curve {
+            RewardCurve::Linear => rounds as u128 * self.reward_per_session(),
+            RewardCurve::FrontLoaded { half_life_sessions } => {
+                let num_sessions = (self.end_date - self.start_date) / SESSION_INTERVAL;
+                (U256::from(self.emission_amount())
+                    * front_loaded_weight(rounds, *half_life_sessions, num_sessions)
+                    / front_loaded_weight(num_sessions, *half_life_sessions, num_sessions))
+                .as_u128()
+            }
+        }
+    }
 }
 
 impl StakingContract {
     pub(crate) fn internal_deposit_farm_tokens(
         &mut self,
         token_id: &AccountId,
+        creator: &AccountId,
         name: String,
         amount: Balance,
         start_date: Timestamp,
         end_date: Timestamp,
+        curve: RewardCurve,
     ) {
-        assert!(start_date >= env::block_timestamp(), "ERR_FARM_TOO_EARLY");
+        assert!(
+            start_date + START_DATE_TOLERANCE >= env::block_timestamp(),
+            "ERR_FARM_TOO_EARLY"
+        );
+        let start_date = std::cmp::max(start_date, env::block_timestamp());
         assert!(end_date > start_date + SESSION_INTERVAL, "ERR_FARM_DATE");
         assert!(amount > 0, "ERR_FARM_AMOUNT_NON_ZERO");
         assert!(
             amount / ((end_date - start_date) / SESSION_INTERVAL) as u128 > 0,
             "ERR_FARM_AMOUNT_TOO_SMALL"
         );
+        curve.assert_valid((end_date - start_date) / SESSION_INTERVAL);
+        assert!(
+            self.active_farms.iter().all(|farm_id| {
+                let farm = self.internal_get_farm(*farm_id);
+                !farm.is_active() || farm.name != name
+            }),
+            "ERR_DUPLICATE_FARM_NAME"
+        );
         self.farms.push(&Farm {
             name,
             token_id: token_id.clone(),
@@ -70,6 +355,12 @@ impl StakingContract {
                 reward_per_share: U256::zero(),
                 reward_round: 0,
             },
+            curve,
+            creator: Some(creator.clone()),
+            created_at: Some(env::block_timestamp()),
+            emission_amount: None,
+            recorded_amount: Some(0),
+            failed_transfer_at: None,
         });
         self.active_farms.push(self.farms.len() - 1);
     }
@@ -114,6 +405,7 @@ impl StakingContract {
             };
         }
         farm.end_date = end_date;
+        farm.emission_amount = None;
 
         assert!(
             farm.end_date > farm.start_date + SESSION_INTERVAL,
@@ -124,10 +416,16 @@ impl StakingContract {
             farm.amount / ((farm.end_date - farm.start_date) / SESSION_INTERVAL) as u128 > 0,
             "ERR_FARM_AMOUNT_TOO_SMALL"
         );
+        farm.curve.assert_valid((farm.end_date - farm.start_date) / SESSION_INTERVAL);
 
         self.farms.replace(farm_id, &farm);
     }
 
+    /// Returns gas for the transfers of the given token, the default if it wasn't set by owner.
+    pub(crate) fn internal_get_farm_token_gas(&self, token_id: &AccountId) -> FarmTokenGas {
+        self.farm_token_gas.get(token_id).unwrap_or_default()
+    }
+
     pub(crate) fn internal_get_farm(&self, farm_id: u64) -> Farm {
         self.farms.get(farm_id).expect("ERR_NO_FARM")
     }
@@ -137,7 +435,18 @@ impl StakingContract {
         farm: &Farm,
         total_staked: Balance,
     ) -> Option<RewardDistribution> {
-        if farm.start_date > env::block_timestamp() {
+        self.internal_calculate_distribution_at(farm, total_staked, env::block_timestamp())
+    }
+
+    /// Calculates the distribution of the farm at the given timestamp, which must not be before
+    /// the last distribution.
+    pub(crate) fn internal_calculate_distribution_at(
+        &self,
+        farm: &Farm,
+        total_staked: Balance,
+        timestamp: Timestamp,
+    ) -> Option<RewardDistribution> {
+        if farm.start_date > timestamp {
             // Farm hasn't started.
             return None;
         }
@@ -146,28 +455,45 @@ impl StakingContract {
             // Farm has ended.
             return Some(distribution);
         }
-        distribution.reward_round = (env::block_timestamp() - farm.start_date) / SESSION_INTERVAL;
-        let reward_per_session =
-            farm.amount / (farm.end_date - farm.start_date) as u128 * SESSION_INTERVAL as u128;
-        let mut reward_added = (distribution.reward_round - farm.last_distribution.reward_round)
-            as u128
-            * reward_per_session;
-        if farm.last_distribution.undistributed < reward_added {
+        distribution.reward_round = (timestamp - farm.start_date) / SESSION_INTERVAL;
+        let num_sessions = (farm.end_date - farm.start_date) / SESSION_INTERVAL;
+        let mut reward_added = farm.distributed_after(distribution.reward_round)
+            - farm.distributed_after(farm.last_distribution.reward_round);
+        // The rounding dust of the previous rounds stays in `undistributed`, so the last step
+        // sweeps it to the stakers together with the rest of the farm.
+        let is_last_step = farm.last_distribution.undistributed <= reward_added
+            || distribution.reward_round >= num_sessions;
+        if is_last_step {
             // Last step when the last tokens are getting distributed.
             reward_added = farm.last_distribution.undistributed;
-            let increase_reward_round = (reward_added / reward_per_session) as u64;
-            distribution.reward_round = farm.last_distribution.reward_round + increase_reward_round;
-            if increase_reward_round as u128 * reward_per_session < reward_added {
-                // Fix the rounding.
-                distribution.reward_round += 1;
+            if farm.curve == RewardCurve::Linear {
+                let reward_per_session = farm.reward_per_session();
+                let increase_reward_round = (reward_added / reward_per_session) as u64;
+                distribution.reward_round =
+                    farm.last_distribution.reward_round + increase_reward_round;
+                if increase_reward_round as u128 * reward_per_session < reward_added {
+                    // Fix the rounding.
+                    distribution.reward_round += 1;
+                }
             }
         }
-        distribution.undistributed -= reward_added;
-        if total_staked == 0 {
+        let reward_attributed = if total_staked == 0 {
             distribution.reward_per_share = U256::zero();
+            reward_added
         } else {
-            distribution.reward_per_share = farm.last_distribution.reward_per_share
-                + U256::from(reward_added) * U256::from(DENOMINATOR) / U256::from(total_staked);
+            let reward_per_share_added =
+                U256::from(reward_added) * U256::from(DENOMINATOR) / U256::from(total_staked);
+            distribution.reward_per_share =
+                farm.last_distribution.reward_per_share + reward_per_share_added;
+            // Rounded down, the shares never receive more than the added reward.
+            (reward_per_share_added * U256::from(total_staked) / U256::from(DENOMINATOR)).as_u128()
+        };
+        if is_last_step {
+            // The rounding of the last step is written off, it's less than one yocto per
+            // `DENOMINATOR` of shares.
+            distribution.undistributed = 0;
+        } else {
+            distribution.undistributed -= reward_attributed;
         }
         Some(distribution)
     }
@@ -210,11 +536,29 @@ impl StakingContract {
     ) {
         let (new_user_rps, claim_amount) =
             self.internal_unclaimed_balance(&account, farm_id, &mut farm);
+        if claim_amount == 0
+            && account
+                .last_farm_reward_per_share
+                .get(&farm_id)
+                .map_or(new_user_rps.is_zero(), |user_rps| *user_rps == new_user_rps)
+        {
+            // Nothing has changed for this account since the last distribution.
+            return;
+        }
         if !account.is_burn_account {
             account
                 .last_farm_reward_per_share
                 .insert(farm_id, new_user_rps);
             *account.amounts.entry(farm.token_id.clone()).or_default() += claim_amount;
+            if claim_amount > 0 {
+                *account.farm_amounts.entry(farm_id).or_default() += claim_amount;
+                if let Some(recorded_amount) = farm.recorded_amount.as_mut() {
+                    *recorded_amount += claim_amount;
+                }
+                let total_distributed = self.total_distributed.get(&farm.token_id).unwrap_or(0);
+                self.total_distributed
+                    .insert(&farm.token_id, &(total_distributed + claim_amount));
+            }
             env::log_str(&format!(
                 "Record {} {} reward from farm #{}",
                 claim_amount, farm.token_id, farm_id
@@ -222,14 +566,33 @@ impl StakingContract {
         }
     }
 
+    /// Returns true if the remaining gas covers the distribution of all active farms.
+    pub(crate) fn internal_has_distribution_gas(&self) -> bool {
+        env::prepaid_gas().0 - env::used_gas().0 >= self.internal_distribution_gas().0
+    }
+
+    /// Returns the gas to distribute the rewards of all active farms to an account.
+    fn internal_distribution_gas(&self) -> Gas {
+        Gas(GAS_PER_FARM_DISTRIBUTION.0 * self.active_farms.len() as u64)
+    }
+
     /// Distribute all rewards for the given user.
+    /// Fails before changing any state if there is not enough gas to go through all active farms.
+    /// Callbacks that can't be retried check `internal_has_distribution_gas` instead of failing.
     pub(crate) fn internal_distribute_all_rewards(&mut self, mut account: &mut Account) {
+        assert!(self.internal_has_distribution_gas(), "ERR_TOO_MANY_FARMS_FOR_GAS");
         let old_active_farms = self.active_farms.clone();
         self.active_farms = vec![];
         for farm_id in old_active_farms.into_iter() {
             if let Some(mut farm) = self.farms.get(farm_id) {
+                let reward_round = farm.last_distribution.reward_round;
+                let recorded_amount = farm.recorded_amount;
                 self.internal_distribute_reward(&mut account, farm_id, &mut farm);
-                self.farms.replace(farm_id, &farm);
+                if farm.last_distribution.reward_round != reward_round
+                    || farm.recorded_amount != recorded_amount
+                {
+                    self.farms.replace(farm_id, &farm);
+                }
                 // TODO: currently all farms continue to be active.
                 // if farm.is_active() {
                 self.active_farms.push(farm_id);
@@ -243,44 +606,192 @@ impl StakingContract {
         account_id: &AccountId,
         token_id: &AccountId,
         amount: Balance,
+        farm_id: Option<u64>,
     ) {
         let mut account = self.internal_get_account(&account_id);
         *account.amounts.entry(token_id.clone()).or_default() += amount;
+        if let Some(farm_id) = farm_id {
+            *account.farm_amounts.entry(farm_id).or_default() += amount;
+        }
+        // The deposit reverts the claim, so it's not claimed anymore.
+        if let Some(total_claimed) = account.total_claimed.get_mut(token_id) {
+            *total_claimed = total_claimed.saturating_sub(amount);
+        }
         self.internal_save_account(&account_id, &account);
     }
 
+    /// Records the failed transfer of the claimed token on the farm it was claimed from, or on all
+    /// the active farms of the token if they were claimed together.
+    fn internal_record_failed_transfer(&mut self, token_id: &AccountId, farm_id: Option<u64>) {
+        let farm_ids = match farm_id {
+            Some(farm_id) => vec![farm_id],
+            None => self.active_farms.clone(),
+        };
+        for farm_id in farm_ids {
+            if let Some(mut farm) = self.farms.get(farm_id) {
+                if &farm.token_id == token_id {
+                    farm.failed_transfer_at = Some(env::block_timestamp());
+                    self.farms.replace(farm_id, &farm);
+                }
+            }
+        }
+    }
+
+    /// Asserts that the prepaid gas covers the transfer of the token, its callback and the
+    /// leftovers. The delegated claim needs the configured get owner gas and leftovers on top,
+    /// and the distribution of the farms in the callback.
+    fn assert_claim_gas(&self, token_id: &AccountId, delegated: bool) {
+        let token_gas = self.internal_get_farm_token_gas(token_id);
+        let mut required_gas = token_gas.transfer_gas + token_gas.resolve_gas + GAS_LEFTOVERS;
+        if delegated {
+            required_gas = required_gas
+                + self.delegated_claim_gas.get_owner_gas
+                + self.delegated_claim_gas.gas_leftovers
+                + self.internal_distribution_gas();
+        }
+        assert!(env::prepaid_gas() >= required_gas, "Not enough gas for claim");
+    }
+
+    /// Claims all the rewards in the given token.
+    /// `delegated` is set when the owner of `send_account_id` claims for `claim_account_id`.
     fn internal_claim(
         &mut self,
         token_id: &AccountId,
         claim_account_id: &AccountId,
         send_account_id: &AccountId,
+        delegated: bool,
     ) -> Promise {
+        let amount = self.internal_take_claim(token_id, claim_account_id);
+        self.internal_send_reward(
+            token_id,
+            claim_account_id,
+            send_account_id,
+            amount,
+            None,
+            delegated,
+        )
+    }
+
+    /// Deducts all the rewards in the given token from the account and returns the amount.
+    fn internal_take_claim(
+        &mut self,
+        token_id: &AccountId,
+        claim_account_id: &AccountId,
+    ) -> Balance {
         let mut account = self.internal_get_account(&claim_account_id);
         self.internal_distribute_all_rewards(&mut account);
         let amount = account.amounts.remove(&token_id).unwrap_or(0);
         assert!(amount > 0, "ERR_ZERO_AMOUNT");
+        // All the farms of this token are claimed together.
+        let farms = &self.farms;
+        account.farm_amounts.retain(|farm_id, _| {
+            farms
+                .get(*farm_id)
+                .map_or(true, |farm| &farm.token_id != token_id)
+        });
+        *account.total_claimed.entry(token_id.clone()).or_default() += amount;
+        self.internal_save_account(&claim_account_id, &account);
+        amount
+    }
+
+    fn internal_claim_farm(
+        &mut self,
+        farm_id: u64,
+        claim_account_id: &AccountId,
+        send_account_id: &AccountId,
+    ) -> Promise {
+        let farm = self.internal_get_farm(farm_id);
+        let mut account = self.internal_get_account(&claim_account_id);
+        self.internal_distribute_all_rewards(&mut account);
+        let amount = account.farm_amounts.remove(&farm_id).unwrap_or(0);
+        assert!(amount > 0, "ERR_ZERO_AMOUNT");
+        let token_amount = account
+            .amounts
+            .get_mut(&farm.token_id)
+            .expect("INTERNAL_FAIL");
+        *token_amount -= amount;
+        if *token_amount == 0 {
+            account.amounts.remove(&farm.token_id);
+        }
+        *account
+            .total_claimed
+            .entry(farm.token_id.clone())
+            .or_default() += amount;
+        self.internal_save_account(&claim_account_id, &account);
+        self.internal_send_reward(
+            &farm.token_id,
+            claim_account_id,
+            send_account_id,
+            amount,
+            Some(farm_id),
+            false,
+        )
+    }
+
+    /// Transfers already deducted reward to `send_account_id`.
+    /// If the transfer fails, the reward is returned to `claim_account_id`.
+    fn internal_send_reward(
+        &mut self,
+        token_id: &AccountId,
+        claim_account_id: &AccountId,
+        send_account_id: &AccountId,
+        amount: Balance,
+        farm_id: Option<u64>,
+        delegated: bool,
+    ) -> Promise {
         env::log_str(&format!(
             "{} receives {} of {} from {}",
             send_account_id, amount, token_id, claim_account_id
         ));
-        self.internal_save_account(&claim_account_id, &account);
-        ext_fungible_token::ft_transfer(
+        let gas = self.internal_get_farm_token_gas(token_id);
+        let promise = ext_fungible_token::ft_transfer(
             send_account_id.clone(),
             U128(amount),
             None,
             token_id.clone(),
             1,
-            GAS_FOR_FT_TRANSFER,
+            gas.transfer_gas,
         )
         .then(ext_self::callback_post_withdraw_reward(
             token_id.clone(),
             // Return funds to the account that was deducted from vs caller.
             claim_account_id.clone(),
             U128(amount),
+            farm_id,
             env::current_account_id(),
             0,
-            GAS_FOR_RESOLVE_TRANSFER,
-        ))
+            gas.resolve_gas,
+        ));
+        events::emit_farm_claim(
+            claim_account_id,
+            send_account_id,
+            token_id,
+            amount,
+            farm_id,
+            delegated,
+        );
+        promise
+    }
+
+    /// Returns the reward deducted by `claim_and_register` back to the account.
+    fn internal_return_claim(
+        &mut self,
+        token_id: &AccountId,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.internal_user_token_deposit(account_id, token_id, amount, None);
+        env::log_str(&format!("Returned {} {} to {}", amount, token_id, account_id));
+        events::emit_farm_claim_refund(account_id, token_id, amount, None);
+    }
+
+    /// Refunds the part of the `claim_and_register` deposit to the account.
+    /// The deposit is tracked in the total balance until it leaves the pool.
+    fn internal_refund_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        self.last_total_balance -= amount;
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(amount);
+        }
     }
 }
 
@@ -299,57 +810,347 @@ impl StakingContract {
         )
         .expect("Failed to parse");
         assert_eq!(owner_id, account_id, "Caller is not an owner");
-        self.internal_claim(&token_id, &delegator_id, &account_id)
+        self.internal_claim(&token_id, &delegator_id, &account_id, true)
     }
 
     /// Callback from depositing funds to the user's account.
     /// If it failed, return funds to the user's account.
+    /// `farm_id` is given if the funds were claimed from a single farm.
     #[private]
     pub fn callback_post_withdraw_reward(
         &mut self,
         token_id: AccountId,
         sender_id: AccountId,
         amount: U128,
+        farm_id: Option<u64>,
     ) {
         if !is_promise_success() {
             // This reverts the changes from the claim function.
-            self.internal_user_token_deposit(&sender_id, &token_id, amount.0);
+            self.internal_user_token_deposit(&sender_id, &token_id, amount.0, farm_id);
+            self.internal_record_failed_transfer(&token_id, farm_id);
             env::log_str(&format!(
                 "Returned {} {} to {}",
                 amount.0, token_id, sender_id
             ));
+            events::emit_farm_claim_refund(&sender_id, &token_id, amount.0, farm_id);
         }
     }
 
+    /// Checks that `account_id` is the owner of the delegator and claims given tokens of the
+    /// delegator in the callback with `callback_gas`.
+    fn internal_claim_delegated(
+        &self,
+        token_id: AccountId,
+        delegator_id: AccountId,
+        account_id: AccountId,
+        callback_gas: Gas,
+    ) -> Promise {
+        Promise::new(delegator_id.clone())
+            .function_call(
+                GET_OWNER_METHOD.to_string(),
+                vec![],
+                0,
+                self.delegated_claim_gas.get_owner_gas,
+            )
+            .then(ext_self::callback_post_get_owner(
+                token_id,
+                delegator_id,
+                account_id,
+                env::current_account_id(),
+                0,
+                callback_gas,
+            ))
+    }
+
     /// Claim given tokens for given account.
     /// If delegator is provided, it will call it's `get_owner` method to confirm that caller
     /// can execute on behalf of this contract.
     /// - Requires one yoctoNEAR. To pass to the ft_transfer call and to guarantee the full access key.
+    /// - Requires enough gas for the transfer of the token, its callback and the leftovers, and
+    ///   for the delegated claim also the get owner gas and its leftovers.
     #[payable]
     pub fn claim(&mut self, token_id: AccountId, delegator_id: Option<AccountId>) -> Promise {
         assert_one_yocto();
+        self.assert_claim_gas(&token_id, delegator_id.is_some());
         let account_id = env::predecessor_account_id();
         if let Some(delegator_id) = delegator_id {
-            Promise::new(delegator_id.clone())
-                .function_call(GET_OWNER_METHOD.to_string(), vec![], 0, GAS_FOR_GET_OWNER)
-                .then(ext_self::callback_post_get_owner(
+            let gas = self.delegated_claim_gas.clone();
+            let callback_gas = (env::prepaid_gas().0 - env::used_gas().0)
+                .checked_sub(gas.get_owner_gas.0 + gas.gas_leftovers.0)
+                .expect("ERR_NOT_ENOUGH_GAS");
+            self.internal_claim_delegated(token_id, delegator_id, account_id, Gas(callback_gas))
+        } else {
+            self.internal_claim(&token_id, &account_id, &account_id, false)
+        }
+    }
+
+    /// Claim given tokens for each of the delegators, the caller must be the owner of all of them.
+    /// Every delegator is checked and claimed independently, so a failure on one of them doesn't
+    /// affect the claims and refunds of the others.
+    /// The gas is split equally between the delegators, each of them leaves its leftovers.
+    /// Returns the joint promise of all the delegated claims.
+    /// - Requires one yoctoNEAR. To guarantee the full access key.
+    /// - Requires enough gas for each delegator to get the owner, distribute the farms, transfer
+    ///   the token and resolve, with the leftovers of the callback.
+    #[payable]
+    pub fn claim_delegated(
+        &mut self,
+        token_id: AccountId,
+        delegator_ids: Vec<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(!delegator_ids.is_empty(), "ERR_NO_DELEGATORS");
+        assert!(
+            delegator_ids.len() <= MAX_DELEGATED_CLAIMS,
+            "ERR_TOO_MANY_DELEGATORS"
+        );
+        let account_id = env::predecessor_account_id();
+        let gas = self.delegated_claim_gas.clone();
+        let gas_per_claim =
+            (env::prepaid_gas().0 - env::used_gas().0) / delegator_ids.len() as u64;
+        let token_gas = self.internal_get_farm_token_gas(&token_id);
+        assert!(
+            gas_per_claim
+                >= gas.get_owner_gas.0
+                    + gas.gas_leftovers.0
+                    + self.internal_distribution_gas().0
+                    + token_gas.transfer_gas.0
+                    + token_gas.resolve_gas.0,
+            "ERR_NOT_ENOUGH_GAS"
+        );
+        let mut promise: Option<Promise> = None;
+        for delegator_id in delegator_ids {
+            let claim = self.internal_claim_delegated(
+                token_id.clone(),
+                delegator_id,
+                account_id.clone(),
+                Gas(gas_per_claim - gas.get_owner_gas.0 - gas.gas_leftovers.0),
+            );
+            promise = Some(match promise {
+                Some(promise) => promise.and(claim),
+                None => claim,
+            });
+        }
+        promise.expect("ERR_NO_DELEGATORS")
+    }
+
+    /// Claims given tokens for the caller, registering the caller in the token storage first.
+    /// Use it when the caller isn't registered in the token yet, otherwise the transfer of `claim`
+    /// fails and the reward is returned to the caller's account in the pool.
+    /// If the caller is already registered, the whole deposit is refunded. Otherwise only the
+    /// minimum storage balance of the token is attached to `storage_deposit` and the rest is
+    /// refunded. If the registration fails, the reward is returned and the deposit is refunded.
+    /// - Requires the attached deposit to cover the minimum storage balance of the token.
+    /// - Requires enough gas for the storage checks, the registration, the transfer and callbacks.
+    #[payable]
+    pub fn claim_and_register(&mut self, token_id: AccountId) -> Promise {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "ERR_NO_STORAGE_DEPOSIT");
+        let token_gas = self.internal_get_farm_token_gas(&token_id);
+        assert!(
+            env::prepaid_gas()
+                >= GAS_FOR_STORAGE_VIEW + token_gas.post_storage_balance_gas() + GAS_LEFTOVERS,
+            "Not enough gas for claim"
+        );
+        let account_id = env::predecessor_account_id();
+        let amount = self.internal_take_claim(&token_id, &account_id);
+        // The deposit is kept out of the rewards until it's refunded or attached.
+        self.last_total_balance += deposit;
+        ext_storage_management::storage_balance_of(
+            account_id.clone(),
+            token_id.clone(),
+            0,
+            GAS_FOR_STORAGE_VIEW,
+        )
+        .then(ext_self::callback_post_storage_balance(
+            token_id,
+            account_id,
+            U128(amount),
+            U128(deposit),
+            env::current_account_id(),
+            0,
+            token_gas.post_storage_balance_gas(),
+        ))
+    }
+
+    /// Callback after getting the storage balance of the `claim_and_register` caller.
+    /// If the caller is registered, refunds the deposit and transfers the reward.
+    #[private]
+    pub fn callback_post_storage_balance(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        deposit: U128,
+    ) {
+        let registered = promise_result_as_success().and_then(|value| {
+            near_sdk::serde_json::from_slice::<Option<near_sdk::serde_json::Value>>(&value)
+                .ok()
+                .map(|balance| balance.is_some())
+        });
+        match registered {
+            Some(true) => {
+                self.internal_refund_storage_deposit(&account_id, deposit.0);
+                self.internal_send_reward(
+                    &token_id,
+                    &account_id,
+                    &account_id,
+                    amount.0,
+                    None,
+                    false,
+                );
+            }
+            Some(false) => {
+                let token_gas = self.internal_get_farm_token_gas(&token_id);
+                ext_storage_management::storage_balance_bounds(
+                    token_id.clone(),
+                    0,
+                    GAS_FOR_STORAGE_VIEW,
+                )
+                .then(ext_self::callback_post_storage_bounds(
+                    token_id,
+                    account_id,
+                    amount,
+                    deposit,
+                    env::current_account_id(),
+                    0,
+                    token_gas.post_storage_bounds_gas(),
+                ));
+            }
+            None => {
+                self.internal_return_claim(&token_id, &account_id, amount.0);
+                self.internal_refund_storage_deposit(&account_id, deposit.0);
+            }
+        }
+    }
+
+    /// Callback after getting the storage bounds of the token for the `claim_and_register` caller.
+    /// Registers the caller with the minimum storage balance and refunds the rest of the deposit.
+    #[private]
+    pub fn callback_post_storage_bounds(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        deposit: U128,
+    ) {
+        let min = promise_result_as_success()
+            .and_then(|value| {
+                near_sdk::serde_json::from_slice::<StorageBalanceBounds>(&value).ok()
+            })
+            .map(|bounds| bounds.min.0);
+        match min {
+            Some(min) if min <= deposit.0 => {
+                self.internal_refund_storage_deposit(&account_id, deposit.0 - min);
+                // The attached minimum leaves the pool too.
+                self.last_total_balance -= min;
+                let token_gas = self.internal_get_farm_token_gas(&token_id);
+                ext_storage_management::storage_deposit(
+                    Some(account_id.clone()),
+                    Some(true),
+                    token_id.clone(),
+                    min,
+                    GAS_FOR_STORAGE_DEPOSIT,
+                )
+                .then(ext_self::callback_post_storage_deposit(
                     token_id,
-                    delegator_id,
                     account_id,
+                    amount,
+                    U128(min),
                     env::current_account_id(),
                     0,
-                    env::prepaid_gas() - env::used_gas() - GAS_FOR_GET_OWNER - GAS_LEFTOVERS,
-                ))
+                    token_gas.post_storage_deposit_gas(),
+                ));
+            }
+            _ => {
+                env::log_str("ERR_NOT_ENOUGH_STORAGE_DEPOSIT");
+                self.internal_return_claim(&token_id, &account_id, amount.0);
+                self.internal_refund_storage_deposit(&account_id, deposit.0);
+            }
+        }
+    }
+
+    /// Callback after registering the `claim_and_register` caller in the token storage.
+    /// Transfers the reward if the registration succeeded, otherwise returns the reward and
+    /// refunds the storage deposit, that the token refunds to the pool.
+    #[private]
+    pub fn callback_post_storage_deposit(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        storage_deposit: U128,
+    ) {
+        if is_promise_success() {
+            self.internal_send_reward(
+                &token_id,
+                &account_id,
+                &account_id,
+                amount.0,
+                None,
+                false,
+            );
         } else {
-            self.internal_claim(&token_id, &account_id, &account_id)
+            self.internal_return_claim(&token_id, &account_id, amount.0);
+            Promise::new(account_id).transfer(storage_deposit.0);
+        }
+    }
+
+    /// Claim tokens accrued from the given farm only.
+    /// `claim(token_id)` transfers everything accrued in `token_id` by all the farms of this token,
+    /// including the amount that can be claimed here. This method transfers only the part
+    /// attributable to `farm_id`, leaving the rewards of other farms with the same token.
+    /// Sends tokens to the `receiver_id` if given, otherwise to the caller.
+    /// - Requires one yoctoNEAR. To pass to the ft_transfer call and to guarantee the full access key.
+    /// - Requires enough gas for the transfer of the token, its callback and the leftovers.
+    #[payable]
+    pub fn claim_farm(&mut self, farm_id: u64, receiver_id: Option<AccountId>) -> Promise {
+        assert_one_yocto();
+        let farm = self.internal_get_farm(farm_id);
+        self.assert_claim_gas(&farm.token_id, false);
+        let account_id = env::predecessor_account_id();
+        let receiver_id = receiver_id.unwrap_or_else(|| account_id.clone());
+        self.internal_claim_farm(farm_id, &account_id, &receiver_id)
+    }
+
+    /// Callback after returning leftover of the stopped or reduced farm.
+    /// If the transfer failed (e.g. receiver doesn't have storage in the token), the leftover is
+    /// returned into the farm and it continues until the previous `end_date`.
+    #[private]
+    pub fn callback_post_stop_farm(&mut self, farm_id: u64, amount: U128, end_date: U64) {
+        if !is_promise_success() {
+            let mut farm = self.internal_get_farm(farm_id);
+            if let Some(emission_amount) = farm.emission_amount {
+                // The reduced farm is still running, scale the schedule back up.
+                let undistributed = farm.last_distribution.undistributed;
+                if undistributed > 0 {
+                    farm.emission_amount = Some(
+                        (U256::from(emission_amount) * U256::from(undistributed + amount.0)
+                            / U256::from(undistributed))
+                        .as_u128(),
+                    );
+                }
+            }
+            farm.amount += amount.0;
+            farm.last_distribution.undistributed += amount.0;
+            farm.end_date = end_date.0;
+            farm.failed_transfer_at = Some(env::block_timestamp());
+            self.farms.replace(farm_id, &farm);
+            env::log_str(&format!(
+                "Returned {} {} to farm #{}",
+                amount.0, farm.token_id, farm_id
+            ));
         }
     }
 
     /// Stops given farm at the current moment.
-    /// Warning: IF OWNER ACCOUNT DOESN'T HAVE STORAGE, THESE FUNDS WILL BE STUCK ON THE STAKING FARM.
+    /// Can be called by the owner or the creator of the farm, the leftover is sent to the caller.
+    /// If the caller doesn't have storage in the token, the leftover is returned to the farm.
     pub fn stop_farm(&mut self, farm_id: u64) -> Promise {
-        self.assert_owner();
         let mut farm = self.internal_get_farm(farm_id);
+        let receiver_id = env::predecessor_account_id();
+        farm.assert_owner_or_creator(&receiver_id);
+        let end_date = farm.end_date;
         let leftover_amount = if let Some(distribution) = self.internal_calculate_distribution(
             &farm,
             self.total_stake_shares - self.total_burn_shares,
@@ -363,13 +1164,126 @@ impl StakingContract {
         farm.amount -= leftover_amount;
         farm.last_distribution.undistributed = 0;
         self.farms.replace(farm_id, &farm);
+        let gas = self.internal_get_farm_token_gas(&farm.token_id);
         ext_fungible_token::ft_transfer(
-            StakingContract::internal_get_owner_id(),
+            receiver_id,
+            U128(leftover_amount),
+            None,
+            farm.token_id.clone(),
+            1,
+            gas.transfer_gas,
+        )
+        .then(ext_self::callback_post_stop_farm(
+            farm_id,
             U128(leftover_amount),
+            U64(end_date),
+            env::current_account_id(),
+            0,
+            gas.resolve_gas,
+        ))
+    }
+
+    /// Owner's method.
+    /// Removes the ended farm from the active farms without transferring anything, e.g. if its
+    /// token contract was deleted and transfers fail. Requires a failed transfer of the farm token
+    /// recorded on the farm. The rewards not recorded to the accounts yet and the leftover of the
+    /// farm are written off, the recorded rewards stay with the accounts.
+    pub fn force_retire_farm(&mut self, farm_id: u64) {
+        self.assert_owner();
+        let mut farm = self.internal_get_farm(farm_id);
+        assert!(env::block_timestamp() >= farm.end_date, "ERR_FARM_NOT_ENDED");
+        assert!(farm.failed_transfer_at.is_some(), "ERR_NO_FAILED_TRANSFER");
+        let position = self
+            .active_farms
+            .iter()
+            .position(|active_farm_id| *active_farm_id == farm_id)
+            .expect("ERR_FARM_NOT_ACTIVE");
+        self.active_farms.remove(position);
+        if let Some(distribution) = self.internal_calculate_distribution(
+            &farm,
+            self.total_stake_shares - self.total_burn_shares,
+        ) {
+            farm.last_distribution = distribution;
+        }
+        match farm.recorded_amount {
+            Some(recorded_amount) => {
+                env::log_str(&format!(
+                    "WARNING: farm #{} is retired, {} {} not recorded to the accounts are \
+                     written off",
+                    farm_id,
+                    farm.amount - recorded_amount,
+                    farm.token_id
+                ));
+                farm.amount = recorded_amount;
+            }
+            None => {
+                // The farm was created before the recorded amount was tracked.
+                env::log_str(&format!(
+                    "WARNING: farm #{} is retired, {} {} and the rewards not recorded to the \
+                     accounts are written off",
+                    farm_id, farm.last_distribution.undistributed, farm.token_id
+                ));
+                farm.amount -= farm.last_distribution.undistributed;
+            }
+        }
+        farm.last_distribution.undistributed = 0;
+        self.farms.replace(farm_id, &farm);
+    }
+
+    /// Reduces the total amount of the given farm to `new_amount`, the difference is sent to
+    /// the caller. The rest of the farm is distributed at the proportionally lower rate by the
+    /// same curve until the same `end_date`. Can't increase the farm (use the top-up instead) or
+    /// go below the amount already distributed (use `stop_farm` instead).
+    /// Can be called by the owner or the creator of the farm.
+    /// If the caller doesn't have storage in the token, the difference is returned to the farm.
+    pub fn reduce_farm(&mut self, farm_id: u64, new_amount: U128) -> Promise {
+        let mut farm = self.internal_get_farm(farm_id);
+        let receiver_id = env::predecessor_account_id();
+        farm.assert_owner_or_creator(&receiver_id);
+        assert!(farm.is_active(), "ERR_FARM_ENDED");
+        assert!(new_amount.0 < farm.amount, "ERR_FARM_CAN_ONLY_REDUCE");
+        let refund_amount = farm.amount - new_amount.0;
+        if let Some(distribution) = self.internal_calculate_distribution(
+            &farm,
+            self.total_stake_shares - self.total_burn_shares,
+        ) {
+            // The farm has started, scale down the rest of its schedule.
+            assert!(refund_amount < distribution.undistributed, "ERR_BELOW_DISTRIBUTED");
+            let undistributed = distribution.undistributed - refund_amount;
+            farm.emission_amount = Some(
+                (U256::from(farm.emission_amount()) * U256::from(undistributed)
+                    / U256::from(distribution.undistributed))
+                .as_u128(),
+            );
+            farm.last_distribution = distribution;
+            farm.last_distribution.undistributed = undistributed;
+        } else {
+            farm.emission_amount = None;
+            farm.last_distribution.undistributed = new_amount.0;
+        }
+        farm.amount = new_amount.0;
+        let num_sessions = (farm.end_date - farm.start_date) / SESSION_INTERVAL;
+        assert!(
+            farm.emission_amount() / num_sessions as u128 > 0,
+            "ERR_FARM_AMOUNT_TOO_SMALL"
+        );
+        self.farms.replace(farm_id, &farm);
+        let gas = self.internal_get_farm_token_gas(&farm.token_id);
+        ext_fungible_token::ft_transfer(
+            receiver_id,
+            U128(refund_amount),
             None,
             farm.token_id.clone(),
             1,
-            GAS_FOR_FT_TRANSFER,
+            gas.transfer_gas,
         )
+        .then(ext_self::callback_post_stop_farm(
+            farm_id,
+            U128(refund_amount),
+            U64(farm.end_date),
+            env::current_account_id(),
+            0,
+            gas.resolve_gas,
+        ))
     }
 }
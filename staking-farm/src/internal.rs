@@ -1,3 +1,4 @@
+use crate::events;
 use crate::owner::{FACTORY_KEY, OWNER_KEY};
 use crate::stake::ext_self;
 use crate::*;
@@ -10,6 +11,28 @@ pub const ZERO_ADDRESS: &str = "000000000000000000000000000000000000000000000000
 /// Minimum amount that will be sent to burn. This is to ensure there is enough storage on the other side.
 pub const MIN_BURN_AMOUNT: Balance = 1694457700619870000000;
 
+/// The first byte of the public key with ED25519 curve.
+const ED25519_CURVE_TYPE: u8 = 0;
+
+/// Asserts that the staking key has ED25519 curve, the only curve the protocol accepts for staking.
+pub(crate) fn assert_staking_key_curve(stake_public_key: &PublicKey) {
+    assert_eq!(
+        stake_public_key.as_bytes()[0],
+        ED25519_CURVE_TYPE,
+        "ERR_INVALID_KEY_CURVE"
+    );
+}
+
+/// Asserts that the reward fee is a valid fraction and doesn't exceed `MAX_REWARD_FEE`.
+pub(crate) fn assert_reward_fee(reward_fee_fraction: &Ratio) {
+    reward_fee_fraction.assert_valid();
+    assert!(
+        u64::from(reward_fee_fraction.numerator) * u64::from(MAX_REWARD_FEE.denominator)
+            <= u64::from(MAX_REWARD_FEE.numerator) * u64::from(reward_fee_fraction.denominator),
+        "ERR_REWARD_FEE_TOO_HIGH"
+    );
+}
+
 impl StakingContract {
     /********************/
     /* Internal methods */
@@ -23,7 +46,7 @@ impl StakingContract {
         // Stakes with the staking public key. If the public key is invalid the entire function
         // call will be rolled back.
         Promise::new(env::current_account_id())
-            .stake(self.total_staked_balance, self.stake_public_key.clone())
+            .stake(self.internal_restake_amount(), self.stake_public_key.clone())
             .then(ext_self::on_stake_action(
                 env::current_account_id(),
                 NO_DEPOSIT,
@@ -31,13 +54,32 @@ impl StakingContract {
             ));
     }
 
+    /// Returns the amount to restake: `total_staked_balance`, but never more than the account
+    /// actually has above the guarantee fund.
+    pub(crate) fn internal_restake_amount(&self) -> Balance {
+        let max_stake = (env::account_balance() + env::account_locked_balance())
+            .saturating_sub(self.guarantee_fund);
+        if self.total_staked_balance > max_stake {
+            log!(
+                "WARNING: total staked balance {} exceeds the available balance, staking {}",
+                self.total_staked_balance,
+                max_stake
+            );
+            max_stake
+        } else {
+            self.total_staked_balance
+        }
+    }
+
     pub(crate) fn internal_deposit(&mut self) -> u128 {
         let account_id = env::predecessor_account_id();
         let mut account = self.internal_get_account(&account_id);
         let amount = env::attached_deposit();
+        assert!(amount > 0, "ERR_ZERO_DEPOSIT");
         account.unstaked += amount;
         self.internal_save_account(&account_id, &account);
         self.last_total_balance += amount;
+        self.internal_add_total_unstaked(amount);
 
         log!(
             "@{} deposited {}. New unstaked balance is {}",
@@ -45,6 +87,7 @@ impl StakingContract {
             amount,
             account.unstaked
         );
+        events::emit_deposit(&account_id, amount, account.unstaked);
         amount
     }
 
@@ -62,6 +105,7 @@ impl StakingContract {
         );
         account.unstaked -= amount;
         self.internal_save_account(&account_id, &account);
+        self.internal_sub_total_unstaked(amount);
 
         log!(
             "@{} withdrawing {}. New unstaked balance is {}",
@@ -74,10 +118,9 @@ impl StakingContract {
         self.last_total_balance -= amount;
     }
 
-    pub(crate) fn internal_stake(&mut self, amount: Balance) {
+    pub(crate) fn internal_stake(&mut self, account_id: &AccountId, amount: Balance) {
         assert!(amount > 0, "Staking amount should be positive");
 
-        let account_id = env::predecessor_account_id();
         let mut account = self.internal_get_account(&account_id);
 
         // Distribute rewards from all the farms for the given user.
@@ -88,7 +131,10 @@ impl StakingContract {
         let num_shares = self.num_shares_from_staked_amount_rounded_down(amount);
         assert!(
             num_shares > 0,
-            "The calculated number of \"stake\" shares received for staking should be positive"
+            "The calculated number of \"stake\" shares received for staking should be positive. \
+             The minimum amount to stake is {}",
+            // The price of a single share.
+            self.staked_amount_from_num_shares_rounded_up(1)
         );
         // The amount of tokens the account will be charged from the unstaked balance.
         // Rounded down to avoid overcharging the account to guarantee that the account can always
@@ -106,6 +152,7 @@ impl StakingContract {
         account.unstaked -= charge_amount;
         account.stake_shares += num_shares;
         self.internal_save_account(&account_id, &account);
+        self.internal_sub_total_unstaked(charge_amount);
 
         // The staked amount that will be added to the total to guarantee the "stake" share price
         // never decreases. The difference between `stake_amount` and `charge_amount` is paid
@@ -124,21 +171,18 @@ impl StakingContract {
             account.unstaked,
             account.stake_shares
         );
+        events::emit_stake(&account_id, charge_amount, num_shares);
         log!(
             "Contract total staked balance is {}. Total number of shares {}",
             self.total_staked_balance,
             self.total_stake_shares
         );
+        events::emit_price_update(self.total_staked_balance, self.total_stake_shares);
     }
 
     pub(crate) fn inner_unstake(&mut self, account_id: &AccountId, amount: u128) {
         assert!(amount > 0, "Unstaking amount should be positive");
 
-        let mut account = self.internal_get_account(&account_id);
-
-        // Distribute rewards from all the farms for the given user.
-        self.internal_distribute_all_rewards(&mut account);
-
         assert!(
             self.total_staked_balance > 0,
             "The contract doesn't have staked balance"
@@ -150,6 +194,22 @@ impl StakingContract {
             num_shares > 0,
             "Invariant violation. The calculated number of \"stake\" shares for unstaking should be positive"
         );
+        self.inner_unstake_shares(account_id, num_shares);
+    }
+
+    /// Unstakes the given number of "stake" shares from the given account.
+    pub(crate) fn inner_unstake_shares(
+        &mut self,
+        account_id: &AccountId,
+        num_shares: NumStakeShares,
+    ) {
+        assert!(num_shares > 0, "Unstaking shares should be positive");
+
+        let mut account = self.internal_get_account(&account_id);
+
+        // Distribute rewards from all the farms for the given user.
+        self.internal_distribute_all_rewards(&mut account);
+
         assert!(
             account.stake_shares >= num_shares,
             "Not enough staked balance to unstake"
@@ -167,6 +227,7 @@ impl StakingContract {
         account.unstaked += receive_amount;
         account.unstaked_available_epoch_height = env::epoch_height() + NUM_EPOCHS_TO_UNLOCK;
         self.internal_save_account(&account_id, &account);
+        self.internal_add_total_unstaked(receive_amount);
 
         // The amount tokens that will be unstaked from the total to guarantee the "stake" share
         // price never decreases. The difference between `receive_amount` and `unstake_amount` is
@@ -193,16 +254,25 @@ impl StakingContract {
             self.total_staked_balance,
             self.total_stake_shares
         );
+        events::emit_price_update(self.total_staked_balance, self.total_stake_shares);
     }
 
+    /// Does nothing if the account has no staked balance.
     pub(crate) fn internal_unstake_all(&mut self, account_id: &AccountId) {
-        // Unstake action always restakes
-        self.internal_ping();
+        let need_to_restake = self.internal_ping();
 
         let account = self.internal_get_account(&account_id);
         let amount = self.staked_amount_from_num_shares_rounded_down(account.stake_shares);
+        if amount == 0 {
+            log!("@{} has nothing to unstake", account_id);
+            if need_to_restake {
+                self.internal_restake();
+            }
+            return;
+        }
         self.inner_unstake(account_id, amount);
 
+        // Unstake action always restakes
         self.internal_restake();
     }
 
@@ -227,11 +297,7 @@ impl StakingContract {
         self.last_epoch_height = epoch_height;
 
         // New total amount (both locked and unlocked balances).
-        // NOTE: We need to subtract `attached_deposit` in case `ping` called from `deposit` call
-        // since the attached deposit gets included in the `account_balance`, and we have not
-        // accounted it yet.
-        let total_balance =
-            env::account_locked_balance() + env::account_balance() - env::attached_deposit();
+        let total_balance = self.internal_total_balance();
 
         assert!(
             total_balance >= self.last_total_balance,
@@ -260,6 +326,7 @@ impl StakingContract {
 
             // Now buying "stake" shares for the contract owner at the new share price.
             let num_owner_shares = self.num_shares_from_staked_amount_rounded_down(owners_fee);
+            self.total_owner_fee_shares += num_owner_shares;
 
             self.internal_add_shares(
                 &AccountId::new_unchecked(ZERO_ADDRESS.to_string()),
@@ -286,12 +353,77 @@ impl StakingContract {
                     num_burn_shares
                 );
             }
+            events::emit_price_update(self.total_staked_balance, self.total_stake_shares);
+
+            if self.recent_rewards.len() >= MAX_RECENT_REWARDS {
+                self.recent_rewards.remove(0);
+            }
+            self.recent_rewards.push((epoch_height, total_reward));
         }
 
         self.last_total_balance = total_balance;
         true
     }
 
+    /// Returns the total balance of the pool (both locked and unlocked balances) to compare with
+    /// `last_total_balance`.
+    pub(crate) fn internal_total_balance(&self) -> Balance {
+        // NOTE: We need to subtract `attached_deposit` in case `ping` called from `deposit` call
+        // since the attached deposit gets included in the `account_balance`, and we have not
+        // accounted it yet.
+        let total_balance =
+            env::account_locked_balance() + env::account_balance() - env::attached_deposit();
+        // The unwrapped NEAR is accounted in its callback. If it has already arrived, the balance
+        // is above the tracked one at least by its amount. Otherwise the rewards that are larger
+        // than it are partially left to the ping after the callback.
+        if total_balance >= self.last_total_balance + self.pending_unwrap_balance {
+            total_balance - self.pending_unwrap_balance
+        } else {
+            total_balance
+        }
+    }
+
+    /// Returns the total staked balance, the total number of "stake" shares and the shares bought
+    /// for the burn and for the owner as they would be after `internal_ping` in the current epoch,
+    /// without modifying the state.
+    pub(crate) fn internal_projected_totals(
+        &self,
+    ) -> (Balance, NumStakeShares, NumStakeShares, NumStakeShares) {
+        let mut total_staked_balance = self.total_staked_balance;
+        let mut total_stake_shares = self.total_stake_shares;
+        if self.last_epoch_height == env::epoch_height() {
+            return (total_staked_balance, total_stake_shares, 0, 0);
+        }
+        let mut num_burn_shares = 0;
+        let mut num_owner_shares = 0;
+        let total_reward = self
+            .internal_total_balance()
+            .saturating_sub(self.last_total_balance);
+        if total_reward > 0 {
+            let burn_fee = self.burn_fee_fraction.multiply(total_reward);
+            let owners_fee = self
+                .reward_fee_fraction
+                .current()
+                .multiply(total_reward - burn_fee);
+            total_staked_balance += total_reward - owners_fee - burn_fee;
+            // Fees buy shares at the price after the delegators' reward is distributed.
+            num_burn_shares = (U256::from(total_stake_shares) * U256::from(burn_fee)
+                / U256::from(total_staked_balance))
+            .as_u128();
+            num_owner_shares = (U256::from(total_stake_shares) * U256::from(owners_fee)
+                / U256::from(total_staked_balance))
+            .as_u128();
+            total_stake_shares += num_burn_shares + num_owner_shares;
+            total_staked_balance += owners_fee + burn_fee;
+        }
+        (
+            total_staked_balance,
+            total_stake_shares,
+            num_burn_shares,
+            num_owner_shares,
+        )
+    }
+
     /// Returns the number of "stake" shares rounded down corresponding to the given staked balance
     /// amount.
     ///
@@ -372,6 +504,8 @@ impl StakingContract {
 
     /// Inner method to save the given account for a given account ID.
     /// If the account balances are 0, the account is deleted instead to release storage.
+    /// Account storage is covered by the contract balance, there is no separate storage
+    /// registration to clear or deposit to refund.
     pub(crate) fn internal_save_account(&mut self, account_id: &AccountId, account: &Account) {
         if account.unstaked > 0 || account.stake_shares > 0 || account.amounts.len() > 0 {
             self.accounts.insert(account_id, &account);
@@ -380,6 +514,20 @@ impl StakingContract {
         }
     }
 
+    /// Adds to the total unstaked balance of all accounts, if it's known.
+    pub(crate) fn internal_add_total_unstaked(&mut self, amount: Balance) {
+        if let Some(total_unstaked_balance) = self.total_unstaked_balance.as_mut() {
+            *total_unstaked_balance += amount;
+        }
+    }
+
+    /// Subtracts from the total unstaked balance of all accounts, if it's known.
+    pub(crate) fn internal_sub_total_unstaked(&mut self, amount: Balance) {
+        if let Some(total_unstaked_balance) = self.total_unstaked_balance.as_mut() {
+            *total_unstaked_balance -= amount;
+        }
+    }
+
     /// Returns current contract version.
     pub(crate) fn internal_get_version() -> String {
         format!("{}:{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
@@ -401,3 +549,49 @@ impl StakingContract {
         )
     }
 }
+
+#[cfg(feature = "test-invariants")]
+impl StakingContract {
+    /// Asserts the accounting invariants of the pool for test harnesses and fuzzing.
+    /// Iterates over all the accounts, so it's only compiled with the `test-invariants` feature.
+    /// `unowned_stake_shares` are the shares minted at the initialization that don't belong to
+    /// any account, tracked by the harness. The totals unknown for the pools migrated from the
+    /// previous versions are not checked.
+    pub fn assert_invariants(&self, unowned_stake_shares: Option<NumStakeShares>) {
+        let mut total_account_shares: NumStakeShares = 0;
+        let mut total_account_unstaked: Balance = 0;
+        for account in self.accounts.values() {
+            total_account_shares += account.stake_shares;
+            total_account_unstaked += account.unstaked;
+        }
+        if let Some(unowned_stake_shares) = unowned_stake_shares {
+            assert_eq!(
+                total_account_shares + unowned_stake_shares,
+                self.total_stake_shares,
+                "ERR_INVARIANT_TOTAL_STAKE_SHARES"
+            );
+        } else {
+            assert!(
+                total_account_shares <= self.total_stake_shares,
+                "ERR_INVARIANT_TOTAL_STAKE_SHARES"
+            );
+        }
+        if let Some(total_unstaked_balance) = self.total_unstaked_balance {
+            assert_eq!(
+                total_account_unstaked, total_unstaked_balance,
+                "ERR_INVARIANT_TOTAL_UNSTAKED_BALANCE"
+            );
+        }
+        // Every share must be redeemable for at least what the accounts were charged, and the
+        // difference paid from the guarantee fund must stay within the balance of the pool.
+        assert!(
+            self.staked_amount_from_num_shares_rounded_down(total_account_shares)
+                <= self.total_staked_balance,
+            "ERR_INVARIANT_SHARES_NOT_BACKED"
+        );
+        assert!(
+            self.total_staked_balance + total_account_unstaked <= self.last_total_balance,
+            "ERR_INVARIANT_TOTAL_BALANCE"
+        );
+    }
+}
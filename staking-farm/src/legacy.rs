@@ -0,0 +1,55 @@
+use crate::*;
+
+/// State of the contract in 1.0.0 and 1.1.0.
+#[derive(BorshDeserialize)]
+pub struct StakingContractV1 {
+    pub stake_public_key: PublicKey,
+    pub last_epoch_height: EpochHeight,
+    pub last_total_balance: Balance,
+    pub total_stake_shares: NumStakeShares,
+    pub total_staked_balance: Balance,
+    pub total_burn_shares: NumStakeShares,
+    pub reward_fee_fraction: UpdatableRewardFee,
+    pub burn_fee_fraction: Ratio,
+    pub accounts: UnorderedMap<AccountId, Account>,
+    pub farms: Vector<Farm>,
+    pub active_farms: Vec<u64>,
+    pub paused: bool,
+    pub authorized_users: UnorderedSet<AccountId>,
+    pub authorized_farm_tokens: UnorderedSet<AccountId>,
+}
+
+impl From<StakingContractV1> for StakingContract {
+    fn from(old: StakingContractV1) -> Self {
+        Self {
+            stake_public_key: old.stake_public_key,
+            last_epoch_height: old.last_epoch_height,
+            last_total_balance: old.last_total_balance,
+            total_stake_shares: old.total_stake_shares,
+            total_staked_balance: old.total_staked_balance,
+            total_burn_shares: old.total_burn_shares,
+            reward_fee_fraction: old.reward_fee_fraction,
+            burn_fee_fraction: old.burn_fee_fraction,
+            accounts: old.accounts,
+            farms: old.farms,
+            active_farms: old.active_farms,
+            paused: old.paused,
+            authorized_users: old.authorized_users,
+            authorized_farm_tokens: old.authorized_farm_tokens,
+            total_distributed: UnorderedMap::new(StorageKeys::TotalDistributed),
+            guarantee_fund: STAKE_SHARE_PRICE_GUARANTEE_FUND,
+            total_owner_fee_shares: 0,
+            farm_token_gas: UnorderedMap::new(StorageKeys::FarmTokenGas),
+            pool_metadata: PoolMetadata::default(),
+            delegated_claim_gas: DelegatedClaimGas::default(),
+            // Summing up all the accounts may not fit into the gas of the migration.
+            total_unstaked_balance: None,
+            wnear_token_id: None,
+            pending_unwrap_balance: 0,
+            created_epoch: old.last_epoch_height,
+            require_token_allowlist: true,
+            last_upgrade_epoch: None,
+            recent_rewards: Vec::new(),
+        }
+    }
+}
@@ -11,12 +11,18 @@ use near_sdk::{
 use uint::construct_uint;
 
 use crate::account::{Account, NumStakeShares};
-use crate::farm::Farm;
-pub use crate::views::{HumanReadableAccount, HumanReadableFarm, PoolSummary};
+use crate::farm::{DelegatedClaimGas, Farm, FarmTokenGas};
+pub use crate::farm::{FarmStatus, RewardCurve, GAS_PER_FARM_DISTRIBUTION};
+pub use crate::views::{
+    AccountDetails, AccountFarmReward, ContractConfig, FeeSchedule, HumanReadableAccount,
+    HumanReadableFarm, PoolSummary,
+};
 
 mod account;
+mod events;
 mod farm;
 mod internal;
+mod legacy;
 mod owner;
 mod stake;
 #[cfg(test)]
@@ -27,15 +33,36 @@ mod views;
 /// The amount of gas given to complete internal `on_stake_action` call.
 const ON_STAKE_ACTION_GAS: Gas = Gas(20_000_000_000_000);
 
-/// The amount of yocto NEAR the contract dedicates to guarantee that the "share" price never
-/// decreases. It's used during rounding errors for share -> amount conversions.
+/// The default amount of yocto NEAR the contract dedicates to guarantee that the "share" price
+/// never decreases. It's used during rounding errors for share -> amount conversions.
 const STAKE_SHARE_PRICE_GUARANTEE_FUND: Balance = 1_000_000_000_000;
 
 /// There is no deposit balance attached.
 const NO_DEPOSIT: Balance = 0;
 
 /// Maximum number of active farms at one time.
-const MAX_NUM_ACTIVE_FARMS: usize = 3;
+pub const MAX_NUM_ACTIVE_FARMS: usize = 3;
+
+/// Maximum fraction of the reward that can be burnt.
+const MAX_BURN_FEE: Ratio = Ratio {
+    numerator: 3,
+    denominator: 10,
+};
+
+/// Maximum fraction of the reward that goes to the owner.
+const MAX_REWARD_FEE: Ratio = Ratio {
+    numerator: 1,
+    denominator: 2,
+};
+
+/// Number of the most recent epochs with rewards kept in `recent_rewards`.
+const MAX_RECENT_REWARDS: usize = 30;
+
+/// Maximum number of authorized users given at the initialization.
+const MAX_NUM_INITIAL_AUTHORIZED_USERS: usize = 10;
+
+/// Maximum length of each of the pool metadata fields.
+const MAX_POOL_METADATA_LENGTH: usize = 256;
 
 /// The number of epochs required for the locked balance to become unlocked.
 /// NOTE: The actual number of epochs when the funds are unlocked is 3. But there is a corner case
@@ -55,6 +82,8 @@ pub enum StorageKeys {
     Farms,
     AuthorizedUsers,
     AuthorizedFarmTokens,
+    TotalDistributed,
+    FarmTokenGas,
 }
 
 /// Tracking balance for burning.
@@ -97,6 +126,11 @@ impl UpdatableRewardFee {
         &self.next_reward_fee_fraction
     }
 
+    /// Epoch height from which the next reward fee fraction applies.
+    pub fn available_epoch_height(&self) -> EpochHeight {
+        self.available_epoch_height
+    }
+
     pub fn set(&mut self, next_reward_fee_fraction: Ratio) {
         if env::epoch_height() >= self.available_epoch_height {
             self.reward_fee_fraction = self.next_reward_fee_fraction.clone();
@@ -104,6 +138,16 @@ impl UpdatableRewardFee {
         self.next_reward_fee_fraction = next_reward_fee_fraction;
         self.available_epoch_height = env::epoch_height() + NUM_EPOCHS_TO_UNLOCK
     }
+
+    /// Cancels the next reward fee fraction, if it's not applied yet.
+    pub fn cancel(&mut self) {
+        assert!(
+            env::epoch_height() < self.available_epoch_height,
+            "ERR_NO_PENDING_REWARD_FEE"
+        );
+        self.next_reward_fee_fraction = self.reward_fee_fraction.clone();
+        self.available_epoch_height = 0;
+    }
 }
 
 #[near_bindgen]
@@ -148,6 +192,37 @@ pub struct StakingContract {
     /// Authorized tokens for farms.
     /// Required because any contract can call method with ft_transfer_call, so must verify that contract will accept it.
     pub authorized_farm_tokens: UnorderedSet<AccountId>,
+    /// Total amount of farmed tokens recorded to the accounts per token.
+    pub total_distributed: UnorderedMap<AccountId, Balance>,
+    /// The amount of yocto NEAR set aside at the initialization to guarantee that the "share"
+    /// price never decreases.
+    pub guarantee_fund: Balance,
+    /// The total number of "stake" shares minted to the owner as the reward fee.
+    pub total_owner_fee_shares: NumStakeShares,
+    /// Gas for the transfers of the farm tokens that need more than the default.
+    pub farm_token_gas: UnorderedMap<AccountId, FarmTokenGas>,
+    /// Description of the pool set by the owner.
+    pub pool_metadata: PoolMetadata,
+    /// Gas for the claims on behalf of the delegator contracts.
+    pub delegated_claim_gas: DelegatedClaimGas,
+    /// The total unstaked balance of all accounts, that can be withdrawn or staked.
+    /// Unknown for the pools migrated from the previous versions, summing up all the accounts
+    /// may not fit into the gas of the migration.
+    pub total_unstaked_balance: Option<Balance>,
+    /// Wrapped NEAR token, that can be transferred to the pool to unwrap and stake it.
+    pub wnear_token_id: Option<AccountId>,
+    /// The amount of wNEAR being unwrapped to stake, that's not in `last_total_balance` yet.
+    /// The NEAR may arrive before the callback, so it's not taken as the reward by a ping.
+    pub pending_unwrap_balance: Balance,
+    /// The epoch height when the pool was initialized.
+    /// Pools migrated from the previous versions use the last epoch height of the migration.
+    pub created_epoch: EpochHeight,
+    /// Whether farms can only be created with the tokens from `authorized_farm_tokens`.
+    pub require_token_allowlist: bool,
+    /// The epoch height of the last code upgrade with the state migration.
+    pub last_upgrade_epoch: Option<EpochHeight>,
+    /// Total rewards received in the most recent epochs, oldest first.
+    pub recent_rewards: Vec<(EpochHeight, Balance)>,
 }
 
 impl Default for StakingContract {
@@ -182,6 +257,28 @@ impl Ratio {
     }
 }
 
+/// Description of the pool and its validator for explorers and wallets.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolMetadata {
+    pub description: Option<String>,
+    pub website: Option<String>,
+    pub logo_url: Option<String>,
+}
+
+impl PoolMetadata {
+    pub fn assert_valid(&self) {
+        for field in [&self.description, &self.website, &self.logo_url] {
+            assert!(
+                field.as_ref().map_or(0, |value| value.len()) <= MAX_POOL_METADATA_LENGTH,
+                "ERR_METADATA_TOO_LONG"
+            );
+        }
+    }
+}
+
 #[near_bindgen]
 impl StakingContract {
     /// Initializes the contract with the given owner_id, initial staking public key (with ED25519
@@ -190,21 +287,44 @@ impl StakingContract {
     /// The entire current balance of this contract will be used to stake. This allows contract to
     /// always maintain staking shares that can't be unstaked or withdrawn.
     /// It prevents inflating the price of the share too much.
+    ///
+    /// `guarantee_fund` is taken from the balance to cover the rounding errors, defaults to
+    /// `STAKE_SHARE_PRICE_GUARANTEE_FUND`.
+    /// `initial_farm_tokens` are authorized for farms from the start.
+    /// `authorized_users` are allowed to add farms from the start.
+    /// `start_paused` creates the pool with the staking paused, so it can be configured before
+    /// `resume_staking`. The staking key is still validated.
     #[init]
     pub fn new(
         owner_id: AccountId,
         stake_public_key: PublicKey,
         reward_fee_fraction: Ratio,
         burn_fee_fraction: Ratio,
+        guarantee_fund: Option<U128>,
+        initial_farm_tokens: Option<Vec<AccountId>>,
+        authorized_users: Option<Vec<AccountId>>,
+        start_paused: Option<bool>,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
-        reward_fee_fraction.assert_valid();
+        internal::assert_staking_key_curve(&stake_public_key);
+        internal::assert_reward_fee(&reward_fee_fraction);
+        // Zero denominator means no burn fee.
+        assert!(
+            u64::from(burn_fee_fraction.numerator) * u64::from(MAX_BURN_FEE.denominator)
+                <= u64::from(MAX_BURN_FEE.numerator) * u64::from(burn_fee_fraction.denominator),
+            "ERR_BURN_FEE_TOO_HIGH"
+        );
         assert!(
             env::is_valid_account_id(owner_id.as_bytes()),
             "The owner account ID is invalid"
         );
         let account_balance = env::account_balance();
-        let total_staked_balance = account_balance - STAKE_SHARE_PRICE_GUARANTEE_FUND;
+        let guarantee_fund = guarantee_fund.map_or(STAKE_SHARE_PRICE_GUARANTEE_FUND, |fund| fund.0);
+        assert!(
+            guarantee_fund > 0 && guarantee_fund < account_balance,
+            "The guarantee fund must be positive and less than the account balance"
+        );
+        let total_staked_balance = account_balance - guarantee_fund;
         assert_eq!(
             env::account_locked_balance(),
             0,
@@ -222,15 +342,50 @@ impl StakingContract {
             accounts: UnorderedMap::new(StorageKeys::Accounts),
             farms: Vector::new(StorageKeys::Farms),
             active_farms: Vec::new(),
-            paused: false,
+            paused: start_paused.unwrap_or(false),
             authorized_users: UnorderedSet::new(StorageKeys::AuthorizedUsers),
             authorized_farm_tokens: UnorderedSet::new(StorageKeys::AuthorizedFarmTokens),
+            total_distributed: UnorderedMap::new(StorageKeys::TotalDistributed),
+            guarantee_fund,
+            total_owner_fee_shares: 0,
+            farm_token_gas: UnorderedMap::new(StorageKeys::FarmTokenGas),
+            pool_metadata: PoolMetadata::default(),
+            delegated_claim_gas: DelegatedClaimGas::default(),
+            total_unstaked_balance: Some(0),
+            wnear_token_id: None,
+            pending_unwrap_balance: 0,
+            created_epoch: env::epoch_height(),
+            require_token_allowlist: true,
+            last_upgrade_epoch: None,
+            recent_rewards: Vec::new(),
         };
+        for token_id in initial_farm_tokens.unwrap_or_default() {
+            assert!(
+                env::is_valid_account_id(token_id.as_bytes()),
+                "The farm token account ID is invalid"
+            );
+            this.authorized_farm_tokens.insert(&token_id);
+        }
+        let authorized_users = authorized_users.unwrap_or_default();
+        assert!(
+            authorized_users.len() <= MAX_NUM_INITIAL_AUTHORIZED_USERS,
+            "ERR_TOO_MANY_AUTHORIZED_USERS"
+        );
+        for account_id in authorized_users {
+            assert!(
+                env::is_valid_account_id(account_id.as_bytes()),
+                "The authorized user account ID is invalid"
+            );
+            this.authorized_users.insert(&account_id);
+        }
         Self::internal_set_owner(&owner_id);
         Self::internal_set_factory(&env::predecessor_account_id());
         Self::internal_set_version();
         // Staking with the current pool to make sure the staking key is valid.
-        this.internal_restake();
+        // Skipped if the pool starts paused, the staking starts with `resume_staking`.
+        if !this.paused {
+            this.internal_restake();
+        }
         this
     }
 
@@ -244,11 +399,19 @@ impl StakingContract {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
     use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
     use near_sdk::json_types::U64;
     use near_sdk::mock::VmAction;
     use near_sdk::serde_json::json;
-    use near_sdk::test_utils::{get_created_receipts, testing_env_with_promise_results};
+    use near_sdk::test_utils::{
+        get_created_receipts, get_logs, testing_env_with_promise_results, VMContextBuilder,
+    };
+    use near_sdk::{testing_env, PromiseOrValue};
+
+    use crate::farm::GAS_PER_FARM_DISTRIBUTION;
+    use crate::internal::ZERO_ADDRESS;
 
     use crate::test_utils::tests::*;
     use crate::test_utils::*;
@@ -279,10 +442,28 @@ mod tests {
 
         emulator.simulate_stake_call();
 
+        // The first failure is retried once with the same amount.
         emulator.update_context(staking(), 0);
         testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
         emulator.contract.on_stake_action();
         let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        if let VmAction::Stake { stake, .. } = receipts[0].actions[0] {
+            assert_eq!(stake, 29999999999999000000000000);
+        } else {
+            panic!("unexpected action");
+        }
+        if let VmAction::FunctionCall { method_name, .. } = &receipts[1].actions[0] {
+            assert_eq!(method_name.as_bytes(), b"on_retry_stake_action")
+        } else {
+            panic!("unexpected action");
+        }
+
+        // The retry failed too, so it unstakes.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator.contract.on_retry_stake_action();
+        let receipts = get_created_receipts();
         assert_eq!(receipts.len(), 1);
         if let VmAction::Stake { stake, .. } = receipts[0].actions[0] {
             assert_eq!(stake, 0);
@@ -291,6 +472,264 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_restake_transient_fail() {
+        let pub_key = "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+            .parse()
+            .unwrap();
+        let mut emulator = Emulator::new(owner(), pub_key, zero_fee());
+        emulator.update_context(bob(), 0);
+        emulator.contract.internal_restake();
+        emulator.simulate_stake_call();
+
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator.contract.on_stake_action();
+        assert_eq!(get_created_receipts().len(), 2);
+
+        // The retry succeeded, so it stays staked.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(vec![]),
+        );
+        emulator.contract.on_retry_stake_action();
+        assert!(get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn test_deposit_and_stake_events() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), ntoy(100));
+        emulator.contract.deposit();
+        let events: Vec<String> = get_logs()
+            .into_iter()
+            .filter(|log| log.starts_with("EVENT_JSON:"))
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains(r#""event":"deposit""#));
+
+        emulator.amount += ntoy(100);
+        emulator.update_context(alice(), ntoy(100));
+        emulator.contract.deposit_and_stake();
+        let events: Vec<String> = get_logs()
+            .into_iter()
+            .filter(|log| log.starts_with("EVENT_JSON:"))
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].contains(r#""event":"deposit""#));
+        assert!(events[1].contains(r#""event":"stake""#));
+        assert!(events[1].contains(r#""account_id":"alice""#));
+        assert!(events[2].contains(r#""event":"price_update""#));
+    }
+
+    #[test]
+    fn test_burn_shares() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.contract.burn_fee_fraction = Ratio {
+            numerator: 1,
+            denominator: 10,
+        };
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        assert_eq!(emulator.contract.get_burn_shares().0, 0);
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        let zero_address = AccountId::new_unchecked(ZERO_ADDRESS.to_string());
+        assert!(emulator.contract.get_burn_shares().0 > 0);
+        assert_eq!(
+            emulator.contract.get_burn_shares().0,
+            emulator
+                .contract
+                .internal_get_account(&zero_address)
+                .stake_shares
+        );
+        assert_eq!(
+            emulator.contract.get_burn_staked_balance(),
+            emulator.contract.get_account_staked_balance(zero_address)
+        );
+    }
+
+    #[test]
+    fn test_account_shares() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        assert_eq!(emulator.contract.get_account_shares(alice()).0, 0);
+        let total_shares = emulator.contract.get_total_shares().0;
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        // The share price starts at 1.
+        assert_eq!(emulator.contract.get_account_shares(alice()).0, ntoy(1_000_000));
+        assert_eq!(emulator.contract.get_total_shares().0, total_shares + ntoy(1_000_000));
+        assert_eq!(
+            emulator.contract.get_total_shares().0,
+            emulator.contract.total_stake_shares
+        );
+    }
+
+    #[test]
+    fn test_recent_rewards() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        assert!(emulator.contract.get_recent_rewards().is_empty());
+        for _ in 0..MAX_RECENT_REWARDS + 2 {
+            let locked_amount = emulator.locked_amount;
+            emulator.skip_epochs(1);
+            let reward = emulator.locked_amount - locked_amount;
+            emulator.contract.ping();
+            assert_eq!(
+                emulator.contract.get_recent_rewards().last(),
+                Some(&(U64(emulator.epoch_height), U128(reward)))
+            );
+        }
+        let recent_rewards = emulator.contract.get_recent_rewards();
+        assert_eq!(recent_rewards.len(), MAX_RECENT_REWARDS);
+        assert_eq!(recent_rewards[0].0, U64(3));
+    }
+
+    #[test]
+    fn test_farm_claim_events() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        let events: Vec<String> = get_logs()
+            .into_iter()
+            .filter(|log| log.contains(r#""event":"farm_claim""#))
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains(r#""claim_account_id":"alice""#));
+        assert!(events[0].contains(r#""token_id":"bob""#));
+        assert!(events[0].contains(r#""delegated":false"#));
+
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator.contract.callback_post_withdraw_reward(bob(), alice(), U128(ntoy(1)), None);
+        let events: Vec<String> = get_logs()
+            .into_iter()
+            .filter(|log| log.contains(r#""event":"farm_claim_refund""#))
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains(r#""account_id":"alice""#));
+
+        // Sending the reward to another receiver is not a delegated claim.
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim_farm(0, Some(charlie()));
+        let farm_claim = get_logs()
+            .into_iter()
+            .find(|log| log.contains(r#""event":"farm_claim""#))
+            .unwrap();
+        assert!(farm_claim.contains(r#""send_account_id":"charlie""#));
+        assert!(farm_claim.contains(r#""delegated":false"#));
+
+        // The owner claiming for the delegator is.
+        emulator.skip_epochs(1);
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(b"\"charlie\"".to_vec()),
+        );
+        emulator
+            .contract
+            .callback_post_get_owner(bob(), alice(), charlie());
+        let farm_claim = get_logs()
+            .into_iter()
+            .find(|log| log.contains(r#""event":"farm_claim""#))
+            .unwrap();
+        assert!(farm_claim.contains(r#""claim_account_id":"alice""#));
+        assert!(farm_claim.contains(r#""delegated":true"#));
+    }
+
+    #[test]
+    fn test_price_update_events() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        let price_updates = |logs: Vec<String>| {
+            logs.into_iter()
+                .filter(|log| log.contains(r#""event":"price_update""#))
+                .count()
+        };
+        assert_eq!(price_updates(get_logs()), 1);
+        assert!(get_logs().iter().any(|log| log.contains(&format!(
+            r#""total_staked_balance":"{}""#,
+            emulator.contract.total_staked_balance
+        ))));
+
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake(U128(ntoy(1000)));
+        assert_eq!(price_updates(get_logs()), 1);
+    }
+
+    #[test]
+    fn test_restake_capped_by_balance() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake(U128(ntoy(900_000)));
+        emulator.simulate_stake_call();
+        // The unstaked balance hasn't settled on the account yet.
+        emulator.amount -= ntoy(900_000);
+        emulator.locked_amount -= ntoy(10);
+        emulator.update_context(bob(), 0);
+        emulator.contract.internal_restake();
+        let receipts = get_created_receipts();
+        if let VmAction::Stake { stake, .. } = receipts[0].actions[0] {
+            assert_eq!(
+                stake,
+                emulator.amount + emulator.locked_amount - emulator.contract.guarantee_fund
+            );
+            assert!(stake < emulator.contract.total_staked_balance);
+        } else {
+            panic!("unexpected action");
+        }
+        assert!(get_logs()[0].starts_with("WARNING: total staked balance"));
+    }
+
     #[test]
     fn test_deposit_withdraw() {
         let mut emulator = Emulator::new(
@@ -309,11 +748,38 @@ mod tests {
             emulator.contract.get_account_unstaked_balance(bob()).0,
             deposit_amount
         );
-        emulator.contract.withdraw(deposit_amount.into());
+        emulator.contract.withdraw(Some(deposit_amount.into()));
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(bob()).0,
+            0u128
+        );
+    }
+
+    #[test]
+    fn test_withdraw_all_available() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        let deposit_amount = ntoy(1_000_000);
+        emulator.update_context(bob(), deposit_amount);
+        emulator.contract.deposit();
+        emulator.amount += deposit_amount;
+        emulator.update_context(bob(), 0);
+        emulator.contract.withdraw(Some(U128(ntoy(300_000))));
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(bob()).0,
+            ntoy(700_000)
+        );
+        emulator.contract.withdraw(None);
         assert_eq!(
             emulator.contract.get_account_unstaked_balance(bob()).0,
             0u128
         );
+        assert_eq!(emulator.contract.get_total_unstaked_balance(), Some(U128(0)));
     }
 
     #[test]
@@ -430,6 +896,11 @@ mod tests {
         assert_eq_in_near!(acc.unstaked_balance.0, deposit_amount / 2);
         assert_eq_in_near!(acc.staked_balance.0, deposit_amount / 2 + ntoy(10));
         assert!(!acc.can_withdraw);
+        assert_eq!(
+            emulator.contract.get_account_unlock_epoch(bob()),
+            U64(emulator.epoch_height + NUM_EPOCHS_TO_UNLOCK)
+        );
+        assert_eq!(emulator.contract.get_account_unlock_epoch(charlie()), U64(0));
 
         assert!(!emulator
             .contract
@@ -441,6 +912,43 @@ mod tests {
             .is_account_unstaked_balance_available(bob()),);
     }
 
+    #[test]
+    fn test_unstake_all_and_queue_withdraw() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(bob(), ntoy(1_000_000));
+        emulator.update_context(bob(), 0);
+        emulator.contract.unstake(U128(ntoy(400_000)));
+        emulator.simulate_stake_call();
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK);
+        emulator.update_context(bob(), 0);
+        let unstaked = emulator.contract.get_account_unstaked_balance(bob()).0;
+        assert!(emulator
+            .contract
+            .is_account_unstaked_balance_available(bob()));
+
+        emulator.contract.unstake_all_and_queue_withdraw();
+        // The available balance is withdrawn.
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, bob());
+        if let VmAction::Transfer { deposit } = receipts[0].actions[0] {
+            assert_eq!(deposit, unstaked);
+        } else {
+            panic!("unexpected action");
+        }
+        // The newly unstaked balance is locked.
+        assert_eq!(emulator.contract.get_account_staked_balance(bob()).0, 0);
+        assert!(emulator.contract.get_account_unstaked_balance(bob()).0 > ntoy(600_000));
+        assert!(!emulator
+            .contract
+            .is_account_unstaked_balance_available(bob()));
+    }
+
     #[test]
     fn test_stake_all_unstake_all() {
         let mut emulator = Emulator::new(
@@ -481,26 +989,65 @@ mod tests {
         );
     }
 
-    /// Test that two can delegate and then undelegate their funds and rewards at different time.
     #[test]
-    fn test_two_delegates() {
+    fn test_staked_balance_projected() {
         let mut emulator = Emulator::new(
             owner(),
             "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
                 .parse()
                 .unwrap(),
-            zero_fee(),
+            Ratio {
+                numerator: 10,
+                denominator: 100,
+            },
         );
-        emulator.update_context(alice(), ntoy(1_000_000));
-        emulator.contract.deposit();
-        emulator.amount += ntoy(1_000_000);
-        emulator.update_context(alice(), 0);
-        emulator.contract.stake(ntoy(1_000_000).into());
-        emulator.simulate_stake_call();
-        emulator.skip_epochs(3);
-        emulator.update_context(bob(), ntoy(1_000_000));
+        emulator.deposit_and_stake(bob(), ntoy(1_000_000));
+        let locked_amount = emulator.locked_amount;
+        emulator.skip_epochs(1);
+        emulator.locked_amount = locked_amount + ntoy(100_000);
+        emulator.update_context(bob(), 0);
 
-        emulator.contract.deposit();
+        // Rewards are not distributed until ping.
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(bob()).0,
+            ntoy(1_000_000)
+        );
+        let projected = emulator.contract.get_account_staked_balance_projected(bob()).0;
+        let projected_owner = emulator
+            .contract
+            .get_account_staked_balance_projected(owner())
+            .0;
+        emulator.contract.ping();
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(bob()).0,
+            projected
+        );
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(owner()).0,
+            projected_owner
+        );
+    }
+
+    /// Test that two can delegate and then undelegate their funds and rewards at different time.
+    #[test]
+    fn test_two_delegates() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), ntoy(1_000_000));
+        emulator.contract.deposit();
+        emulator.amount += ntoy(1_000_000);
+        emulator.update_context(alice(), 0);
+        emulator.contract.stake(ntoy(1_000_000).into());
+        emulator.simulate_stake_call();
+        emulator.skip_epochs(3);
+        emulator.update_context(bob(), ntoy(1_000_000));
+
+        emulator.contract.deposit();
         emulator.amount += ntoy(1_000_000);
         emulator.update_context(bob(), 0);
         emulator.contract.stake(ntoy(1_000_000).into());
@@ -539,6 +1086,309 @@ mod tests {
 
         let accounts = emulator.contract.get_accounts(2, 10);
         assert_eq!(accounts.len(), 0);
+
+        // The page is exactly filled.
+        let accounts = emulator.contract.get_accounts(0, 2);
+        assert_eq!(accounts.len(), 2);
+
+        // Doesn't overflow with the large limit.
+        let accounts = emulator.contract.get_accounts(1, u64::MAX);
+        assert_eq!(accounts.len(), 1);
+        let accounts = emulator.contract.get_accounts(u64::MAX, u64::MAX);
+        assert_eq!(accounts.len(), 0);
+    }
+
+    #[test]
+    fn test_total_unstaked_balance() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            Ratio {
+                numerator: 10,
+                denominator: 100,
+            },
+        );
+        let assert_total_unstaked = |emulator: &Emulator| {
+            let sum: Balance = emulator
+                .contract
+                .get_accounts(0, 100)
+                .iter()
+                .map(|account| account.unstaked_balance.0)
+                .sum();
+            assert_eq!(emulator.contract.get_total_unstaked_balance(), Some(U128(sum)));
+        };
+        emulator.update_context(alice(), ntoy(1_000));
+        emulator.contract.deposit();
+        emulator.amount += ntoy(1_000);
+        assert_total_unstaked(&emulator);
+        emulator.update_context(alice(), 0);
+        emulator.contract.stake(U128(ntoy(600)));
+        emulator.simulate_stake_call();
+        assert_total_unstaked(&emulator);
+        emulator.deposit_and_stake(bob(), ntoy(500));
+        assert_total_unstaked(&emulator);
+
+        emulator.skip_epochs(1);
+        emulator.update_context(bob(), 0);
+        emulator.contract.unstake(U128(ntoy(100)));
+        emulator.simulate_stake_call();
+        assert_total_unstaked(&emulator);
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake_all();
+        emulator.simulate_stake_call();
+        assert_total_unstaked(&emulator);
+
+        emulator.skip_epochs(4);
+        emulator.update_context(alice(), 0);
+        emulator.contract.withdraw(Some(U128(ntoy(300))));
+        assert_total_unstaked(&emulator);
+        emulator.update_context(bob(), 0);
+        emulator.contract.stake_all();
+        emulator.simulate_stake_call();
+        assert_total_unstaked(&emulator);
+    }
+
+    #[test]
+    fn test_total_unstaked_balance_unknown() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), ntoy(1_000));
+        emulator.contract.deposit();
+        emulator.amount += ntoy(1_000);
+        // The state of the pool migrated from the previous version.
+        emulator.contract.total_unstaked_balance = None;
+        emulator.unowned_stake_shares = None;
+        emulator.update_context(alice(), 0);
+        emulator.contract.withdraw(Some(U128(ntoy(400))));
+        emulator.amount -= ntoy(400);
+        emulator.contract.stake(U128(ntoy(500)));
+        emulator.simulate_stake_call();
+        assert_eq!(emulator.contract.get_total_unstaked_balance(), None);
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(alice()).0,
+            ntoy(100)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The minimum amount to stake is 2")]
+    fn test_stake_less_than_share_price() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        // The share price grows above 1 with the rewards.
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        emulator.update_context(bob(), 1);
+        emulator.contract.deposit();
+        emulator.update_context(bob(), 0);
+        emulator.contract.stake(U128(1));
+    }
+
+    fn wnear() -> AccountId {
+        "wnear".parse().unwrap()
+    }
+
+    #[test]
+    fn test_stake_wnear() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_wnear_token_id(Some(wnear()));
+        emulator.update_context(wnear(), 0);
+        assert!(matches!(
+            emulator
+                .contract
+                .ft_on_transfer(alice(), U128(ntoy(100)), "stake".to_string()),
+            PromiseOrValue::Promise(_)
+        ));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, wnear());
+        if let VmAction::FunctionCall { method_name, .. } = &receipts[0].actions[0] {
+            assert_eq!(method_name.as_bytes(), b"near_withdraw");
+        } else {
+            panic!("unexpected action");
+        }
+
+        // NEAR arrives before the callback.
+        emulator.amount += ntoy(100);
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert_eq!(
+            emulator.contract.callback_post_unwrap(alice(), U128(ntoy(100))).0,
+            0
+        );
+        emulator.simulate_stake_call();
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(alice()).0,
+            ntoy(100)
+        );
+        assert_eq!(
+            emulator.contract.get_last_total_balance().0,
+            emulator.amount + emulator.locked_amount
+        );
+        assert_eq!(emulator.contract.pending_unwrap_balance, 0);
+    }
+
+    #[test]
+    fn test_stake_wnear_ping_before_callback() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1000));
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_wnear_token_id(Some(wnear()));
+        emulator.update_context(wnear(), 0);
+        emulator
+            .contract
+            .ft_on_transfer(alice(), U128(ntoy(100)), "stake".to_string());
+        assert_eq!(emulator.contract.pending_unwrap_balance, ntoy(100));
+
+        // The epoch changes before the NEAR arrives, the rewards are below the unwrapped amount.
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        assert_eq!(
+            emulator.contract.get_last_total_balance().0,
+            emulator.amount + emulator.locked_amount
+        );
+
+        // The NEAR arrives and the epoch changes before the callback.
+        emulator.amount += ntoy(100);
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        assert_eq!(
+            emulator.contract.get_last_total_balance().0,
+            emulator.amount + emulator.locked_amount - ntoy(100)
+        );
+
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(vec![]),
+        );
+        emulator.contract.callback_post_unwrap(alice(), U128(ntoy(100)));
+        emulator.simulate_stake_call();
+        assert_eq!(emulator.contract.pending_unwrap_balance, 0);
+        assert_eq!(
+            emulator.contract.get_last_total_balance().0,
+            emulator.amount + emulator.locked_amount
+        );
+    }
+
+    #[test]
+    fn test_stake_wnear_failed_unwrap() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_wnear_token_id(Some(wnear()));
+        let last_total_balance = emulator.contract.get_last_total_balance();
+        emulator.update_context(wnear(), 0);
+        emulator.contract.ft_on_transfer(alice(), U128(ntoy(100)), "stake".to_string());
+
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        // All the tokens are refunded.
+        assert_eq!(
+            emulator.contract.callback_post_unwrap(alice(), U128(ntoy(100))).0,
+            ntoy(100)
+        );
+        assert_eq!(
+            emulator.contract.get_last_total_balance(),
+            last_total_balance
+        );
+        assert_eq!(emulator.contract.pending_unwrap_balance, 0);
+        assert_eq!(emulator.contract.get_account_staked_balance(alice()).0, 0);
+    }
+
+    #[test]
+    fn test_stake_wnear_not_enough_gas_for_farms() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_wnear_token_id(Some(wnear()));
+        emulator.update_context(wnear(), 0);
+        emulator
+            .contract
+            .ft_on_transfer(alice(), U128(ntoy(100)), "stake".to_string());
+
+        // Without restaking, the callback only needs the gas for the farm.
+        emulator.contract.paused = true;
+        emulator.amount += ntoy(100);
+        emulator.update_context(staking(), 0);
+        emulator.context.prepaid_gas = GAS_PER_FARM_DISTRIBUTION.0 - 1;
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(vec![]),
+        );
+        // The NEAR is credited, but stays unstaked.
+        assert_eq!(
+            emulator.contract.callback_post_unwrap(alice(), U128(ntoy(100))).0,
+            0
+        );
+        assert_eq!(emulator.contract.get_account_staked_balance(alice()).0, 0);
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(alice()).0,
+            ntoy(100)
+        );
+    }
+
+    #[test]
+    fn test_stake_not_wnear() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.update_context(bob(), 0);
+        let result = emulator
+            .contract
+            .ft_on_transfer(owner(), U128(ntoy(100)), "stake".to_string());
+        if let PromiseOrValue::Value(unused) = result {
+            assert_eq!(unused.0, ntoy(100));
+        } else {
+            panic!("unexpected result");
+        }
     }
 
     #[test]
@@ -608,7 +1458,7 @@ mod tests {
         emulator.deposit_and_stake(alice(), ntoy(1_000_000));
 
         let farm = emulator.contract.get_farm(0);
-        assert_eq!(farm.name, "test".to_string());
+        assert_eq!(farm.name, "test0".to_string());
         assert_eq!(farm.token_id, bob());
         assert_eq!(farm.start_date.0, 0);
         assert_eq!(farm.end_date.0, ONE_EPOCH_TS * 4);
@@ -657,27 +1507,33 @@ mod tests {
             ntoy(1) / 100,
         ));
 
+        let claimed = emulator.contract.get_unclaimed_reward(alice(), 0).0;
         emulator.update_context(alice(), 1);
         emulator.contract.claim(bob(), None);
         assert_eq!(emulator.contract.get_unclaimed_reward(alice(), 0).0, 0);
-    }
+        assert_eq!(
+            emulator.contract.get_account_total_claimed(alice(), bob()).0,
+            claimed
+        );
 
-    fn add_farm(emulator: &mut Emulator, amount: Balance) {
-        emulator.update_context(bob(), 0);
-        emulator.contract.ft_on_transfer(
-            owner(),
-            U128(amount),
-            json!({
-                "name": "test".to_string(),
-                "start_date": U64(0),
-                "end_date": U64(ONE_EPOCH_TS * 4),
-            })
-            .to_string(),
+        // Failed transfer returns the tokens and reverts the claimed amount.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator
+            .contract
+            .callback_post_withdraw_reward(bob(), alice(), U128(claimed), None);
+        assert_eq!(
+            emulator.contract.get_account_total_claimed(alice(), bob()).0,
+            0
+        );
+        assert_eq!(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            claimed
         );
     }
 
     #[test]
-    fn test_stop_farm() {
+    fn test_account_staking_details() {
         let mut emulator = Emulator::new(
             owner(),
             "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
@@ -690,26 +1546,453 @@ mod tests {
         add_farm(&mut emulator, ntoy(100));
         emulator.deposit_and_stake(alice(), ntoy(1_000_000));
         emulator.skip_epochs(1);
-        assert!(almost_equal(
-            emulator.contract.get_unclaimed_reward(alice(), 0).0,
-            ntoy(25),
-            ntoy(1) / 100
-        ));
-        emulator.update_context(owner(), 0);
-        emulator.contract.stop_farm(0);
-        emulator.skip_epochs(1);
-        // Deposit alice, start farm, wait for 1 epoch.
-        // Stop farm, wait for another epoch - the amount of farmed tokens is the same.
-        assert!(almost_equal(
-            emulator.contract.get_unclaimed_reward(alice(), 0).0,
-            ntoy(25),
-            ntoy(1) / 100
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake(U128(ntoy(1000)));
+        emulator.simulate_stake_call();
+
+        let details = emulator.contract.get_account_staking_details(alice());
+        let account = emulator.contract.get_account(alice());
+        assert_eq!(details.staked_balance, account.staked_balance);
+        assert_eq!(details.unstaked_balance, account.unstaked_balance);
+        assert!(!details.can_withdraw);
+        assert_eq!(
+            details.unstaked_available_epoch_height.0,
+            emulator.epoch_height + NUM_EPOCHS_TO_UNLOCK
+        );
+        assert_eq!(details.farms.len(), 1);
+        assert_eq!(details.farms[0].token_id, bob());
+        assert_eq!(
+            details.farms[0].unclaimed,
+            emulator.contract.get_unclaimed_reward(alice(), 0)
+        );
+    }
+
+    #[test]
+    fn test_claim_and_register() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        let reward = emulator.contract.get_unclaimed_reward(alice(), 0).0;
+        let last_total_balance = emulator.contract.last_total_balance;
+        let deposit = ntoy(1) / 100;
+        emulator.update_context(alice(), deposit);
+        emulator.contract.claim_and_register(bob());
+        emulator.amount += deposit;
+        assert_eq!(emulator.contract.get_unclaimed_reward(alice(), 0).0, 0);
+        assert_eq!(
+            emulator.contract.last_total_balance,
+            last_total_balance + deposit
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, bob());
+        if let VmAction::FunctionCall {
+            method_name, args, ..
+        } = &receipts[0].actions[0]
+        {
+            assert_eq!(method_name.as_bytes(), b"storage_balance_of");
+            let args: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_slice(args).unwrap();
+            assert_eq!(args["account_id"], "alice");
+        } else {
+            panic!("unexpected action");
+        }
+
+        // Not registered yet: asks for the storage bounds.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(b"null".to_vec()),
+        );
+        emulator
+            .contract
+            .callback_post_storage_balance(bob(), alice(), U128(reward), U128(deposit));
+        let receipts = get_created_receipts();
+        assert!(matches!(
+            &receipts[0].actions[0],
+            VmAction::FunctionCall { method_name, .. }
+                if method_name.as_bytes() == b"storage_balance_bounds"
+        ));
+
+        // Only the minimum is attached, the rest of the deposit is refunded.
+        let storage_deposit = ntoy(1) / 800;
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(
+                format!("{{\"min\":\"{}\",\"max\":null}}", storage_deposit).into_bytes(),
+            ),
+        );
+        emulator
+            .contract
+            .callback_post_storage_bounds(bob(), alice(), U128(reward), U128(deposit));
+        assert_eq!(emulator.contract.last_total_balance, last_total_balance);
+        let receipts = get_created_receipts();
+        assert!(receipts.iter().any(|receipt| {
+            receipt.receiver_id == alice()
+                && matches!(
+                    &receipt.actions[0],
+                    VmAction::Transfer { deposit: amount } if *amount == deposit - storage_deposit
+                )
+        }));
+        assert!(receipts.iter().any(|receipt| {
+            receipt.receiver_id == bob()
+                && matches!(
+                    &receipt.actions[0],
+                    VmAction::FunctionCall { method_name, deposit, .. }
+                        if method_name.as_bytes() == b"storage_deposit"
+                            && *deposit == storage_deposit
+                )
+        }));
+
+        // Registered: transfers the reward.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(vec![]),
+        );
+        emulator.contract.callback_post_storage_deposit(
+            bob(),
+            alice(),
+            U128(reward),
+            U128(storage_deposit),
+        );
+        let receipts = get_created_receipts();
+        assert!(matches!(
+            &receipts[0].actions[0],
+            VmAction::FunctionCall { method_name, deposit, .. }
+                if method_name.as_bytes() == b"ft_transfer" && *deposit == 1
         ));
     }
 
     #[test]
-    #[should_panic(expected = "ERR_NOT_AUTHORIZED_TOKEN")]
-    fn test_farm_not_authorized_token() {
+    fn test_claim_and_register_already_registered() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        let reward = emulator.contract.get_unclaimed_reward(alice(), 0).0;
+        let last_total_balance = emulator.contract.last_total_balance;
+        let deposit = ntoy(1) / 100;
+        emulator.update_context(alice(), deposit);
+        emulator.contract.claim_and_register(bob());
+        emulator.amount += deposit;
+
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(
+            emulator.context.clone(),
+            PromiseResult::Successful(b"{\"total\":\"1\",\"available\":\"0\"}".to_vec()),
+        );
+        emulator
+            .contract
+            .callback_post_storage_balance(bob(), alice(), U128(reward), U128(deposit));
+        assert_eq!(emulator.contract.last_total_balance, last_total_balance);
+        let receipts = get_created_receipts();
+        assert!(receipts.iter().any(|receipt| {
+            receipt.receiver_id == alice()
+                && matches!(
+                    &receipt.actions[0],
+                    VmAction::Transfer { deposit: amount } if *amount == deposit
+                )
+        }));
+        assert!(receipts.iter().any(|receipt| {
+            receipt.receiver_id == bob()
+                && matches!(
+                    &receipt.actions[0],
+                    VmAction::FunctionCall { method_name, .. }
+                        if method_name.as_bytes() == b"ft_transfer"
+                )
+        }));
+    }
+
+    #[test]
+    fn test_claim_and_register_failed_registration() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        let reward = emulator.contract.get_unclaimed_reward(alice(), 0).0;
+        emulator.update_context(alice(), ntoy(1) / 100);
+        emulator.contract.claim_and_register(bob());
+        emulator.amount += ntoy(1) / 100;
+        assert_eq!(emulator.contract.get_unclaimed_reward(alice(), 0).0, 0);
+
+        let storage_deposit = ntoy(1) / 800;
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator.contract.callback_post_storage_deposit(
+            bob(),
+            alice(),
+            U128(reward),
+            U128(storage_deposit),
+        );
+        assert_eq!(emulator.contract.get_unclaimed_reward(alice(), 0).0, reward);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, alice());
+        assert!(matches!(
+            &receipts[0].actions[0],
+            VmAction::Transfer { deposit } if *deposit == storage_deposit
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas for claim")]
+    fn test_claim_not_enough_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.context.prepaid_gas = 1_000_000_000_000;
+        testing_env!(emulator.context.clone());
+        emulator.contract.claim(bob(), None);
+    }
+
+    #[test]
+    fn test_claim_delegated() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 1);
+        emulator
+            .contract
+            .claim_delegated(bob(), vec![alice(), charlie()]);
+        let receipts = get_created_receipts();
+        for delegator_id in [alice(), charlie()].iter() {
+            assert!(receipts.iter().any(|receipt| {
+                &receipt.receiver_id == delegator_id
+                    && matches!(
+                        &receipt.actions[0],
+                        VmAction::FunctionCall { method_name, .. }
+                            if method_name.as_bytes() == b"get_owner_account_id"
+                    )
+            }));
+        }
+        let callbacks = receipts
+            .iter()
+            .filter(|receipt| {
+                matches!(
+                    &receipt.actions[0],
+                    VmAction::FunctionCall { method_name, .. }
+                        if method_name.as_bytes() == b"callback_post_get_owner"
+                )
+            })
+            .count();
+        assert_eq!(callbacks, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_DELEGATORS")]
+    fn test_claim_delegated_too_many() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 1);
+        emulator.contract.claim_delegated(bob(), vec![alice(); 11]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_STORAGE_DEPOSIT")]
+    fn test_claim_and_register_no_deposit() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), 0);
+        emulator.contract.claim_and_register(bob());
+    }
+
+    #[test]
+    fn test_farm_token_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator
+            .contract
+            .set_farm_token_gas(bob(), U64(50_000_000_000_000), U64(30_000_000_000_000));
+        assert_eq!(
+            emulator.contract.get_farm_token_gas(bob()),
+            (U64(50_000_000_000_000), U64(30_000_000_000_000))
+        );
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        let receipts = get_created_receipts();
+        if let VmAction::FunctionCall { gas, .. } = &receipts[0].actions[0] {
+            assert_eq!(*gas, Gas(50_000_000_000_000));
+        } else {
+            panic!("unexpected action");
+        }
+        if let VmAction::FunctionCall { gas, .. } = &receipts[1].actions[0] {
+            assert_eq!(*gas, Gas(30_000_000_000_000));
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn test_delegated_claim_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator
+            .contract
+            .set_delegated_claim_gas(U64(40_000_000_000_000), U64(30_000_000_000_000));
+        assert_eq!(
+            emulator.contract.get_delegated_claim_gas(),
+            (U64(40_000_000_000_000), U64(30_000_000_000_000))
+        );
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), Some(charlie()));
+        let receipts = get_created_receipts();
+        if let VmAction::FunctionCall {
+            method_name, gas, ..
+        } = &receipts[0].actions[0]
+        {
+            assert_eq!(method_name.as_bytes(), b"get_owner_account_id");
+            assert_eq!(*gas, Gas(40_000_000_000_000));
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_GAS_TOO_HIGH")]
+    fn test_delegated_claim_gas_too_high() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator
+            .contract
+            .set_delegated_claim_gas(U64(60_000_000_000_000), U64(u64::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas for claim")]
+    fn test_delegated_claim_not_enough_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator
+            .contract
+            .set_delegated_claim_gas(U64(60_000_000_000_000), U64(40_000_000_000_000));
+        emulator.update_context(alice(), 1);
+        emulator.context.prepaid_gas = 60_000_000_000_000;
+        testing_env!(emulator.context.clone());
+        emulator.contract.claim(bob(), Some(charlie()));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_claim_delegated_not_enough_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 1);
+        emulator.context.prepaid_gas = 200_000_000_000_000;
+        testing_env!(emulator.context.clone());
+        // Each of the delegators gets 40 Tgas, below the 60 Tgas it needs.
+        emulator.contract.claim_delegated(bob(), vec![alice(); 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_claim_delegated_not_enough_gas_for_farms() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        add_farm(&mut emulator, ntoy(100));
+        emulator.update_context(owner(), 1);
+        emulator.context.prepaid_gas = 130_000_000_000_000;
+        testing_env!(emulator.context.clone());
+        // Each of the delegators gets 65 Tgas, enough without farms, but the callbacks need
+        // 10 Tgas more to distribute the two farms.
+        emulator
+            .contract
+            .claim_delegated(bob(), vec![alice(), charlie()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas for claim")]
+    fn test_claim_farm_not_enough_gas() {
         let mut emulator = Emulator::new(
             owner(),
             "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
@@ -717,7 +2000,1797 @@ mod tests {
                 .unwrap(),
             zero_fee(),
         );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
         add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.context.prepaid_gas = 40_000_000_000_000;
+        testing_env!(emulator.context.clone());
+        emulator.contract.claim_farm(0, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ZERO_AMOUNT")]
+    fn test_claim_twice_before_resolve() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        // The reward is deducted before the transfer, the second claim has nothing to send while
+        // the first transfer is in flight.
+        emulator.contract.claim(bob(), None);
+    }
+
+    #[test]
+    fn test_unknown_account_views() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+
+        let account = emulator.contract.get_account(charlie());
+        assert_eq!(account.unstaked_balance.0, 0);
+        assert_eq!(account.staked_balance.0, 0);
+        assert!(account.can_withdraw);
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(charlie()).0,
+            0
+        );
+        assert_eq!(emulator.contract.get_unclaimed_reward(charlie(), 0).0, 0);
+    }
+
+    #[test]
+    fn test_claimable() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        assert_eq!(emulator.contract.get_claimable(charlie(), bob()).0, 0);
+        assert_eq!(emulator.contract.get_claimable(alice(), charlie()).0, 0);
+        let claimable = emulator.contract.get_claimable(alice(), bob()).0;
+        assert!(almost_equal(claimable, ntoy(50), ntoy(1) / 100));
+
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        let receipts = get_created_receipts();
+        if let VmAction::FunctionCall { args, .. } = &receipts[0].actions[0] {
+            let args: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_slice(args).unwrap();
+            assert_eq!(args["amount"], claimable.to_string());
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn test_claim_without_stake() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake_all();
+        emulator.simulate_stake_call();
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK);
+        emulator.update_context(alice(), 0);
+        emulator.contract.withdraw_all();
+
+        // The account without balance is kept for the farmed tokens.
+        let account = emulator.contract.accounts.get(&alice()).unwrap();
+        assert_eq!(account.stake_shares, 0);
+        assert_eq!(account.unstaked, 0);
+        assert!(almost_equal(
+            account.amounts[&bob()],
+            ntoy(25),
+            ntoy(1) / 100
+        ));
+
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        assert!(emulator.contract.accounts.get(&alice()).is_none());
+    }
+
+    #[test]
+    fn test_claim_farm() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+
+        // Only the first farm is claimed, even though both farms have the same token.
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim_farm(0, None);
+        let account = emulator.contract.accounts.get(&alice()).unwrap();
+        assert!(account.farm_amounts.get(&0).is_none());
+        assert!(almost_equal(
+            account.farm_amounts[&1],
+            ntoy(25),
+            ntoy(1) / 100
+        ));
+        assert_eq!(account.amounts[&bob()], account.farm_amounts[&1]);
+
+        // Claiming the token drains the rest.
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        let account = emulator.contract.accounts.get(&alice()).unwrap();
+        assert!(account.farm_amounts.is_empty());
+        assert!(account.amounts.get(&bob()).is_none());
+    }
+
+    #[test]
+    fn test_unstake_shares() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(3);
+        emulator.update_context(alice(), 0);
+        let shares = emulator.contract.internal_get_account(&alice()).stake_shares;
+        emulator.contract.unstake_shares(U128(shares / 2));
+        emulator.simulate_stake_call();
+        let account = emulator.contract.internal_get_account(&alice());
+        assert_eq!(account.stake_shares, shares - shares / 2);
+        assert!(account.unstaked > 0);
+        assert_eq!(
+            account.unstaked_available_epoch_height,
+            emulator.epoch_height + NUM_EPOCHS_TO_UNLOCK
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough staked balance to unstake")]
+    fn test_unstake_shares_too_many() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(100));
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake_shares(U128(ntoy(101)));
+    }
+
+    #[test]
+    fn test_farm_reward_per_second() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(
+            farm.reward_per_second.0,
+            ntoy(100) * 1_000_000_000 / u128::from(ONE_EPOCH_TS * 4)
+        );
+    }
+
+    #[test]
+    fn test_farm_reward_per_second_large_amount() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(u128::MAX),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(3_000_000_000),
+            })
+            .to_string(),
+        );
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(farm.reward_per_second.0, u128::MAX / 3);
+    }
+
+    #[test]
+    fn test_preview_farm_distribution() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        let reward_per_second = emulator.contract.get_farm(0).reward_per_second.0;
+
+        let (distributed, undistributed) = emulator
+            .contract
+            .preview_farm_distribution(0, U64(ONE_EPOCH_TS * 2));
+        // The rounding of the reward per share stays undistributed.
+        let expected = reward_per_second * u128::from(ONE_EPOCH_TS * 2 / 1_000_000_000);
+        assert!(distributed.0 <= expected);
+        assert!(almost_equal(distributed.0, expected, 1_000));
+        assert_eq!(distributed.0 + undistributed.0, ntoy(100));
+
+        // Clamped to the end of the farm.
+        let at_end = emulator.contract.preview_farm_distribution(0, U64(ONE_EPOCH_TS * 4));
+        let after_end = emulator.contract.preview_farm_distribution(0, U64(ONE_EPOCH_TS * 10));
+        assert_eq!(at_end, after_end);
+        assert!(at_end.0 .0 > distributed.0);
+
+        // The farm is not affected.
+        assert_eq!(emulator.contract.get_farm(0).amount.0, ntoy(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PREVIEW_BEFORE_START")]
+    fn test_preview_farm_distribution_before_start() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm_with_dates(&mut emulator, ONE_EPOCH_TS, ONE_EPOCH_TS * 4);
+        emulator.contract.preview_farm_distribution(0, U64(ONE_EPOCH_TS / 2));
+    }
+
+    #[test]
+    fn test_farm_status() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm_with_dates(&mut emulator, ONE_EPOCH_TS, ONE_EPOCH_TS * 4);
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(farm.status, FarmStatus::Pending);
+        assert!(farm.active);
+        assert_eq!(farm.seconds_remaining, U64(ONE_EPOCH_TS * 4 / 1_000_000_000));
+
+        emulator.skip_epochs(2);
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(farm.status, FarmStatus::Running);
+        assert_eq!(farm.seconds_remaining, U64(ONE_EPOCH_TS * 2 / 1_000_000_000));
+
+        emulator.skip_epochs(2);
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(farm.status, FarmStatus::Ended);
+        assert_eq!(farm.seconds_remaining, U64(0));
+    }
+
+    #[test]
+    fn test_farm_front_loaded() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        // The reward halves after 2 epochs.
+        let half_life_sessions = ONE_EPOCH_TS * 2 / 1_000_000_000;
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(ntoy(90)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+                "curve": { "FrontLoaded": { "half_life_sessions": half_life_sessions } },
+            })
+            .to_string(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+
+        // First half of the farm distributes 2/3 of the amount.
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(30),
+            ntoy(1) / 100
+        ));
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(60),
+            ntoy(1) / 100
+        ));
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(75),
+            ntoy(1) / 100
+        ));
+        emulator.skip_epochs(2);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(90),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FARM_CURVE_HALF_LIFE")]
+    fn test_farm_front_loaded_invalid() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(ntoy(90)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+                "curve": { "FrontLoaded": { "half_life_sessions": 0 } },
+            })
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_total_rewards_distributed() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.deposit_and_stake(charlie(), ntoy(1_000_000));
+        assert_eq!(emulator.contract.get_total_rewards_distributed(bob()).0, 0);
+
+        emulator.skip_epochs(1);
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        emulator.update_context(charlie(), 1);
+        emulator.contract.claim(bob(), None);
+        assert!(almost_equal(
+            emulator.contract.get_total_rewards_distributed(bob()).0,
+            ntoy(25),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    fn test_farm_rewards_fully_distributed() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        let amount = ntoy(100) + 7;
+        add_farm(&mut emulator, amount);
+        let stakers = vec![alice(), charlie(), owner()];
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000) + 3);
+        emulator.deposit_and_stake(charlie(), ntoy(333_333) + 11);
+        emulator.deposit_and_stake(owner(), ntoy(77_777) + 5);
+
+        // Distribute every epoch of the farm, then claim everything after it ends.
+        for _ in 0..3 {
+            emulator.skip_epochs(1);
+            emulator.update_context(alice(), 1);
+            emulator.contract.claim(bob(), None);
+        }
+        emulator.skip_epochs(2);
+        for account_id in stakers.iter() {
+            emulator.update_context(account_id.clone(), 1);
+            emulator.contract.claim(bob(), None);
+        }
+        assert!(!emulator.contract.get_farm(0).active);
+
+        // The initial shares of the pool don't belong to any staker.
+        let staker_shares: NumStakeShares = stakers
+            .iter()
+            .map(|account_id| emulator.contract.internal_get_account(account_id).stake_shares)
+            .sum();
+        let expected = (U256::from(amount) * U256::from(staker_shares)
+            / U256::from(emulator.contract.total_stake_shares))
+        .as_u128();
+        let distributed = emulator.contract.get_total_rewards_distributed(bob()).0;
+        assert!(distributed <= expected);
+        // Less than one yocto per distribution to a staker and the rounding of the last step.
+        let max_dust = 10 + emulator.contract.total_stake_shares / 10u128.pow(24);
+        assert!(expected - distributed <= max_dust);
+    }
+
+    fn add_farm(emulator: &mut Emulator, amount: Balance) {
+        let name = format!("test{}", emulator.contract.farms.len());
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(amount),
+            json!({
+                "name": name,
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+            })
+            .to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_FARMS_FOR_GAS")]
+    fn test_farms_not_enough_gas() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        add_farm(&mut emulator, ntoy(100));
+        emulator.update_context(alice(), ntoy(100));
+        emulator.context.prepaid_gas = GAS_PER_FARM_DISTRIBUTION.0 * 2 - 1;
+        testing_env!(emulator.context.clone());
+        emulator.contract.deposit_and_stake();
+    }
+
+    #[test]
+    fn test_stop_farm() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(25),
+            ntoy(1) / 100
+        ));
+        emulator.update_context(owner(), 0);
+        emulator.contract.stop_farm(0);
+        emulator.skip_epochs(1);
+        // Deposit alice, start farm, wait for 1 epoch.
+        // Stop farm, wait for another epoch - the amount of farmed tokens is the same.
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(25),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    fn test_reduce_farm() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(owner(), 0);
+        emulator.contract.reduce_farm(0, U128(ntoy(60)));
+        let receipts = get_created_receipts();
+        if let VmAction::FunctionCall {
+            method_name, args, ..
+        } = &receipts[0].actions[0]
+        {
+            assert_eq!(method_name.as_bytes(), b"ft_transfer");
+            let args: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_slice(args).unwrap();
+            assert_eq!(args["receiver_id"], "owner");
+            assert_eq!(args["amount"], ntoy(40).to_string());
+        } else {
+            panic!("unexpected action");
+        }
+        let farm = emulator.contract.get_farm(0);
+        assert!(farm.active);
+        assert_eq!(farm.amount.0, ntoy(60));
+        assert_eq!(farm.start_date.0, 0);
+        assert_eq!(farm.end_date.0, ONE_EPOCH_TS * 4);
+
+        // The rest is distributed over the remaining 3 epochs.
+        emulator.skip_epochs(3);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(60),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    fn test_reduce_farm_front_loaded() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        // The reward halves after 2 epochs: 30, 30, 15, 15.
+        let half_life_sessions = ONE_EPOCH_TS * 2 / 1_000_000_000;
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(ntoy(90)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+                "curve": { "FrontLoaded": { "half_life_sessions": half_life_sessions } },
+            })
+            .to_string(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(owner(), 0);
+        emulator.contract.reduce_farm(0, U128(ntoy(60)));
+
+        // The rest of the schedule is halved: 15, 7.5, 7.5.
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(45),
+            ntoy(1) / 100
+        ));
+        emulator.skip_epochs(2);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(60),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FARM_CAN_ONLY_REDUCE")]
+    fn test_reduce_farm_increase() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.update_context(owner(), 0);
+        emulator.contract.reduce_farm(0, U128(ntoy(101)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BELOW_DISTRIBUTED")]
+    fn test_reduce_farm_below_distributed() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(2);
+        emulator.update_context(owner(), 0);
+        emulator.contract.reduce_farm(0, U128(ntoy(40)));
+    }
+
+    #[test]
+    fn test_force_retire_farm() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(4);
+
+        // The transfer of the claim fails, the reward is returned to the account.
+        emulator.update_context(alice(), 1);
+        emulator.contract.claim(bob(), None);
+        let recorded = emulator.contract.accounts.get(&alice()).unwrap().total_claimed[&bob()];
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator
+            .contract
+            .callback_post_withdraw_reward(bob(), alice(), U128(recorded), None);
+
+        emulator.update_context(owner(), 0);
+        emulator.contract.force_retire_farm(0);
+        assert!(emulator.contract.get_active_farms().is_empty());
+        let farm = emulator.contract.get_farm(0);
+        assert!(!farm.active);
+        assert_eq!(farm.amount.0, recorded);
+        // Nothing is transferred.
+        assert!(get_created_receipts().is_empty());
+        // The share of the shares not owned by any account is never recorded.
+        assert!(ntoy(100) > recorded);
+        assert_eq!(
+            get_logs()[0],
+            format!(
+                "WARNING: farm #0 is retired, {} bob not recorded to the accounts are written off",
+                ntoy(100) - recorded
+            )
+        );
+        assert_eq!(
+            emulator.contract.accounts.get(&alice()).unwrap().amounts[&bob()],
+            recorded
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_FAILED_TRANSFER")]
+    fn test_force_retire_farm_no_failed_transfer() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(4);
+        emulator.update_context(owner(), 0);
+        emulator.contract.force_retire_farm(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FARM_NOT_ENDED")]
+    fn test_force_retire_live_farm() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.skip_epochs(1);
+        emulator.update_context(owner(), 0);
+        emulator.contract.force_retire_farm(0);
+    }
+
+    fn add_farm_by_charlie(emulator: &mut Emulator) {
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.contract.add_authorized_user(charlie());
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            charlie(),
+            U128(ntoy(100)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+            })
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_stop_farm_by_creator() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        add_farm_by_charlie(&mut emulator);
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(charlie(), 0);
+        let farm = emulator.contract.get_farm(0);
+        assert_eq!(farm.creator, Some(charlie()));
+        assert_eq!(farm.created_at, Some(U64(0)));
+        emulator.contract.stop_farm(0);
+        assert!(!emulator.contract.get_farm(0).active);
+        // The leftover is sent to the creator.
+        let receipts = get_created_receipts();
+        if let VmAction::FunctionCall {
+            method_name, args, ..
+        } = &receipts[0].actions[0]
+        {
+            assert_eq!(method_name.as_bytes(), b"ft_transfer");
+            let args: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_slice(args).unwrap();
+            assert_eq!(args["receiver_id"], "charlie");
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MUST_BE_OWNER_OR_CREATOR")]
+    fn test_stop_farm_not_creator() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        add_farm_by_charlie(&mut emulator);
+        emulator.update_context(alice(), 0);
+        emulator.contract.stop_farm(0);
+    }
+
+    #[test]
+    fn test_stop_farm_failed_transfer() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.update_context(owner(), 0);
+        emulator.contract.stop_farm(0);
+        assert!(!emulator.contract.get_farm(0).active);
+
+        // Owner is not registered with the token, transfer of the leftover fails.
+        emulator.update_context(staking(), 0);
+        testing_env_with_promise_results(emulator.context.clone(), PromiseResult::Failed);
+        emulator
+            .contract
+            .callback_post_stop_farm(0, U128(ntoy(75)), U64(ONE_EPOCH_TS * 4));
+        let farm = emulator.contract.get_farm(0);
+        assert!(farm.active);
+        assert_eq!(farm.end_date.0, ONE_EPOCH_TS * 4);
+
+        // The farm continues to distribute the returned leftover.
+        emulator.skip_epochs(1);
+        assert!(almost_equal(
+            emulator.contract.get_unclaimed_reward(alice(), 0).0,
+            ntoy(50),
+            ntoy(1) / 100
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_AUTHORIZED_TOKEN")]
+    fn test_farm_not_authorized_token() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        add_farm(&mut emulator, ntoy(100));
+    }
+
+    #[test]
+    fn test_farm_without_token_allowlist() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        assert!(emulator.contract.get_require_token_allowlist());
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_require_token_allowlist(false);
+        assert!(!emulator.contract.get_require_token_allowlist());
+        add_farm(&mut emulator, ntoy(100));
+        assert_eq!(emulator.contract.get_active_farms().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_AUTHORIZED_USER")]
+    fn test_farm_without_token_allowlist_not_authorized_user() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_require_token_allowlist(false);
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            charlie(),
+            U128(ntoy(100)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(0),
+                "end_date": U64(ONE_EPOCH_TS * 4),
+            })
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_authorized_farm_token_changes() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        assert!(emulator.contract.add_authorized_farm_token(&bob()));
+        assert!(!emulator.contract.add_authorized_farm_token(&bob()));
+        assert!(emulator.contract.remove_authorized_farm_token(&bob()));
+        assert!(!emulator.contract.remove_authorized_farm_token(&bob()));
+    }
+
+    #[test]
+    fn test_stake_discrepancy() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), ntoy(100));
+        emulator.contract.deposit_and_stake();
+        // Stake action is not executed yet.
+        let (total_staked_balance, locked_balance) = emulator.contract.get_stake_discrepancy();
+        assert_eq!(locked_balance.0, 0);
+        assert_eq!(
+            total_staked_balance,
+            emulator.contract.get_total_staked_balance()
+        );
+        emulator.amount += ntoy(100);
+        emulator.simulate_stake_call();
+        let (total_staked_balance, locked_balance) = emulator.contract.get_stake_discrepancy();
+        assert_eq!(total_staked_balance, locked_balance);
+        assert_eq!(
+            emulator.contract.get_protocol_locked_balance(),
+            locked_balance
+        );
+        // Before any rewards the tracked total balance is the full balance of the pool.
+        assert_eq!(
+            emulator.contract.get_last_total_balance().0,
+            emulator.amount + emulator.locked_amount
+        );
+    }
+
+    #[test]
+    fn test_owner_fee_earned() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            Ratio {
+                numerator: 10,
+                denominator: 100,
+            },
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        assert_eq!(emulator.contract.get_owner_fee_earned().0, 0);
+        emulator.skip_epochs(1);
+        emulator.contract.ping();
+        let earned = emulator.contract.get_owner_fee_earned().0;
+        assert!(earned > 0);
+        assert_eq!(
+            earned,
+            emulator.contract.get_account_staked_balance(owner()).0
+        );
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        let next_fee = Ratio {
+            numerator: 1,
+            denominator: 10,
+        };
+        emulator.update_context(owner(), 0);
+        emulator.contract.update_reward_fee_fraction(next_fee.clone());
+        let schedule = emulator.contract.get_fee_schedule();
+        assert_eq!(schedule.current_reward_fee, zero_fee());
+        assert_eq!(schedule.next_reward_fee, next_fee);
+        assert_eq!(schedule.reward_fee_effective_epoch.0, NUM_EPOCHS_TO_UNLOCK);
+
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK);
+        let schedule = emulator.contract.get_fee_schedule();
+        assert_eq!(schedule.current_reward_fee, next_fee);
+    }
+
+    #[test]
+    fn test_cancel_pending_reward_fee() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.update_reward_fee_fraction(Ratio {
+            numerator: 1,
+            denominator: 10,
+        });
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK - 1);
+        emulator.update_context(owner(), 0);
+        emulator.contract.cancel_pending_reward_fee();
+        let schedule = emulator.contract.get_fee_schedule();
+        assert_eq!(schedule.current_reward_fee, zero_fee());
+        assert_eq!(schedule.next_reward_fee, zero_fee());
+
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK);
+        assert_eq!(emulator.contract.get_reward_fee_fraction(), zero_fee());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_PENDING_REWARD_FEE")]
+    fn test_cancel_applied_reward_fee() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.update_reward_fee_fraction(Ratio {
+            numerator: 1,
+            denominator: 10,
+        });
+        emulator.skip_epochs(NUM_EPOCHS_TO_UNLOCK);
+        emulator.update_context(owner(), 0);
+        emulator.contract.cancel_pending_reward_fee();
+    }
+
+    #[test]
+    fn test_unstake_all_nothing_staked() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), 0);
+        emulator.contract.unstake_all();
+        assert!(get_created_receipts().is_empty());
+        assert_eq!(emulator.contract.get_number_of_accounts(), 0);
+    }
+
+    #[test]
+    fn test_unstake_burn_twice() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.contract.burn_fee_fraction = Ratio {
+            numerator: 1,
+            denominator: 10,
+        };
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        emulator.contract.unstake_burn();
+        let burn_account_id = AccountId::new_unchecked(ZERO_ADDRESS.to_string());
+        let account = emulator.contract.internal_get_account(&burn_account_id);
+        assert_eq!(account.stake_shares, 0);
+        assert!(account.unstaked > 0);
+        let unlock_epoch = account.unstaked_available_epoch_height;
+
+        emulator.simulate_stake_call();
+        emulator.contract.unstake_burn();
+        let account = emulator.contract.internal_get_account(&burn_account_id);
+        assert_eq!(account.unstaked_available_epoch_height, unlock_epoch);
+    }
+
+    #[test]
+    fn test_guarantee_fund() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        let contract = StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            zero_fee(),
+            Some(U128(ntoy(1))),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.guarantee_fund, ntoy(1));
+        assert_eq!(contract.total_staked_balance, ntoy(29));
+        assert_eq!(contract.total_stake_shares, ntoy(29));
+    }
+
+    #[test]
+    fn test_start_paused() {
+        let mut context = VMContextBuilder::new()
+            .current_account_id(staking())
+            .predecessor_account_id(bob())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context.clone());
+        let mut contract = StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            zero_fee(),
+            None,
+            None,
+            None,
+            Some(true),
+        );
+        assert!(contract.is_staking_paused());
+        assert!(get_created_receipts().is_empty());
+        assert_eq!(contract.total_staked_balance, ntoy(30) - STAKE_SHARE_PRICE_GUARANTEE_FUND);
+
+        context.predecessor_account_id = owner().to_string();
+        testing_env!(context);
+        contract.resume_staking();
+        assert!(!contract.is_staking_paused());
+        let receipts = get_created_receipts();
+        if let VmAction::Stake { stake, .. } = &receipts[0].actions[0] {
+            assert_eq!(*stake, ntoy(30) - STAKE_SHARE_PRICE_GUARANTEE_FUND);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn test_initial_farm_tokens() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        let contract = StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            zero_fee(),
+            None,
+            Some(vec![bob()]),
+            Some(vec![charlie()]),
+            None,
+        );
+        assert_eq!(contract.get_authorized_farm_tokens(), vec![bob()]);
+        assert_eq!(contract.get_authorized_users(), vec![charlie()]);
+    }
+
+    fn secp256k1_key() -> PublicKey {
+        let mut key = vec![1u8];
+        key.extend_from_slice(&[7u8; 64]);
+        PublicKey::try_from(key).unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_KEY_CURVE")]
+    fn test_new_invalid_key_curve() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        StakingContract::new(
+            owner(),
+            secp256k1_key(),
+            zero_fee(),
+            Ratio {
+                numerator: 0,
+                denominator: 0,
+            },
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_KEY_CURVE")]
+    fn test_update_staking_key_invalid_curve() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.update_staking_key(secp256k1_key());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BURN_FEE_TOO_HIGH")]
+    fn test_burn_fee_too_high() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            Ratio {
+                numerator: 31,
+                denominator: 100,
+            },
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REWARD_FEE_TOO_HIGH")]
+    fn test_reward_fee_too_high() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            Ratio {
+                numerator: 51,
+                denominator: 100,
+            },
+            zero_fee(),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REWARD_FEE_TOO_HIGH")]
+    fn test_update_reward_fee_too_high() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.update_reward_fee_fraction(MAX_REWARD_FEE);
+        emulator.contract.update_reward_fee_fraction(Ratio {
+            numerator: 6,
+            denominator: 10,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BURN_FEE_ALREADY_ZERO")]
+    fn test_decrease_zero_burn_fee() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .predecessor_account_id(bob())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        let mut contract = StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            Ratio {
+                numerator: 1,
+                denominator: 10,
+            },
+            None,
+            None,
+            None,
+            None,
+        );
+        // Bob initialized the contract, so it's the factory.
+        contract.decrease_burn_fee_fraction(Ratio {
+            numerator: 0,
+            denominator: 10,
+        });
+        assert_eq!(contract.get_fee_schedule().burn_fee.numerator, 0);
+        contract.decrease_burn_fee_fraction(Ratio {
+            numerator: 0,
+            denominator: 10,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_AUTHORIZED_USERS")]
+    fn test_too_many_initial_authorized_users() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            zero_fee(),
+            None,
+            None,
+            Some(vec![charlie(); MAX_NUM_INITIAL_AUTHORIZED_USERS + 1]),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The guarantee fund must be positive and less than the account balance")]
+    fn test_guarantee_fund_too_large() {
+        let context = VMContextBuilder::new()
+            .current_account_id(owner())
+            .account_balance(ntoy(30))
+            .build();
+        testing_env!(context);
+        StakingContract::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+            zero_fee(),
+            Some(U128(ntoy(30))),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_accounts_total_balance() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(100));
+        emulator.update_context(bob(), ntoy(10));
+        emulator.contract.deposit();
+        assert_eq!(
+            emulator
+                .contract
+                .get_accounts_total_balance(vec![bob(), charlie(), alice()]),
+            vec![
+                U128(ntoy(10)),
+                U128(0),
+                emulator.contract.get_account_total_balance(alice())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unclaimed_rewards_batch() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm(&mut emulator, ntoy(100));
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(1);
+        let zero_address = AccountId::new_unchecked(ZERO_ADDRESS.to_string());
+        assert_eq!(
+            emulator
+                .contract
+                .get_unclaimed_rewards_batch(vec![alice(), charlie(), zero_address], 0),
+            vec![
+                emulator.contract.get_unclaimed_reward(alice(), 0),
+                U128(0),
+                U128(0)
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_ACCOUNTS")]
+    fn test_unclaimed_rewards_batch_too_many() {
+        let emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.contract.get_unclaimed_rewards_batch(vec![alice(); 101], 0);
+    }
+
+    #[test]
+    fn test_pool_summary() {
+        let pub_key: PublicKey = "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+            .parse()
+            .unwrap();
+        let mut emulator = Emulator::new(owner(), pub_key.clone(), zero_fee());
+        emulator.update_context(owner(), 0);
+        emulator.contract.pause_staking();
+        let summary = emulator.contract.get_pool_summary();
+        assert_eq!(summary.stake_public_key, pub_key);
+        assert_eq!(summary.version, emulator.contract.get_version());
+        assert_eq!(
+            emulator.contract.get_state_version(),
+            emulator.contract.get_version()
+        );
+        assert!(summary.paused);
+        assert_eq!(summary.metadata, PoolMetadata::default());
+        assert_eq!(summary.created_epoch, U64(0));
+        emulator.skip_epochs(3);
+        assert_eq!(emulator.contract.get_created_epoch(), U64(0));
+        assert_eq!(emulator.contract.get_last_upgrade_epoch(), None);
+    }
+
+    #[test]
+    fn test_contract_config() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        let config = emulator.contract.get_contract_config();
+        assert_eq!(config.owner_id, owner());
+        assert_eq!(config.factory_id, emulator.contract.get_factory_id());
+        assert_eq!(config.version, emulator.contract.get_version());
+        assert!(!config.paused);
+        assert_eq!(config.reward_fee_fraction, zero_fee());
+        assert_eq!(
+            config.burn_fee_fraction,
+            emulator.contract.get_pool_summary().burn_fee_fraction
+        );
+        assert_eq!(config.num_epochs_to_unlock, U64(NUM_EPOCHS_TO_UNLOCK));
+    }
+
+    #[test]
+    fn test_pool_metadata() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.set_pool_metadata(
+            Some("Test pool".to_string()),
+            Some("https://example.com".to_string()),
+            None,
+        );
+        let metadata = emulator.contract.get_pool_metadata();
+        assert_eq!(metadata.description, Some("Test pool".to_string()));
+        assert_eq!(metadata.website, Some("https://example.com".to_string()));
+        assert_eq!(metadata.logo_url, None);
+        assert_eq!(emulator.contract.get_pool_summary().metadata, metadata);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_METADATA_TOO_LONG")]
+    fn test_pool_metadata_too_long() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator
+            .contract
+            .set_pool_metadata(Some("a".repeat(MAX_POOL_METADATA_LENGTH + 1)), None, None);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.update_context(owner(), 0);
+        // Restakes even if nothing changed since the last ping.
+        let total_staked_balance = emulator.contract.reconcile();
+        assert_eq!(
+            total_staked_balance,
+            emulator.contract.get_total_staked_balance()
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        if let VmAction::Stake { stake, .. } = receipts[0].actions[0] {
+            assert_eq!(stake, total_staked_balance.0);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn test_skim_guarantee_surplus() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.deposit_and_stake(bob(), ntoy(1_000));
+        emulator.skip_epochs(3);
+        emulator.update_context(bob(), 0);
+        emulator.contract.unstake(U128(ntoy(100)));
+        emulator.simulate_stake_call();
+        // The rounding surplus kept by the pool.
+        emulator.amount += 3;
+        emulator.contract.last_total_balance += 3;
+        let alice_staked = emulator.contract.get_account_staked_balance(alice());
+        let bob_staked = emulator.contract.get_account_staked_balance(bob());
+        let bob_unstaked = emulator.contract.get_account_unstaked_balance(bob());
+        let total_staked = emulator.contract.get_total_staked_balance();
+        let total_stake_shares = emulator.contract.total_stake_shares;
+
+        emulator.update_context(owner(), 0);
+        let amount = emulator.contract.skim_guarantee_surplus(charlie());
+        // Only the tiny surplus is sent, the guarantee fund and the initial stake stay.
+        assert!(amount.0 > 0 && amount.0 <= 3);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, charlie());
+        if let VmAction::Transfer { deposit } = receipts[0].actions[0] {
+            assert_eq!(deposit, amount.0);
+        } else {
+            panic!("unexpected action");
+        }
+        assert_eq!(emulator.contract.get_account_staked_balance(charlie()).0, 0);
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(alice()),
+            alice_staked
+        );
+        assert_eq!(
+            emulator.contract.get_account_staked_balance(bob()),
+            bob_staked
+        );
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(bob()),
+            bob_unstaked
+        );
+        assert_eq!(emulator.contract.get_total_staked_balance(), total_staked);
+        assert_eq!(emulator.contract.total_stake_shares, total_stake_shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOTAL_UNSTAKED_UNKNOWN")]
+    fn test_skim_guarantee_surplus_unknown_unstaked() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        // The state of the pool migrated from the previous version.
+        emulator.contract.total_unstaked_balance = None;
+        emulator.update_context(owner(), 0);
+        emulator.contract.skim_guarantee_surplus(charlie());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_SURPLUS")]
+    fn test_skim_guarantee_surplus_twice() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.amount += 3;
+        emulator.contract.last_total_balance += 3;
+        emulator.update_context(owner(), 0);
+        emulator.contract.skim_guarantee_surplus(charlie());
+        emulator.contract.skim_guarantee_surplus(charlie());
+    }
+
+    #[test]
+    #[should_panic(expected = "Can only be called by the owner")]
+    fn test_skim_guarantee_surplus_not_owner() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), 0);
+        emulator.contract.skim_guarantee_surplus(alice());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_AUTHORIZED_USER")]
+    fn test_reconcile_not_authorized() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), 0);
+        emulator.contract.reconcile();
+    }
+
+    #[test]
+    fn test_preview_stake() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        assert_eq!(
+            emulator.contract.preview_stake(U128(ntoy(10))),
+            (U128(ntoy(10)), U128(ntoy(10)))
+        );
+        emulator.deposit_and_stake(alice(), ntoy(1_000_000));
+        emulator.skip_epochs(3);
+        emulator.contract.ping();
+
+        let (num_shares, charge_amount) = emulator.contract.preview_stake(U128(ntoy(10)));
+        assert!(num_shares.0 < ntoy(10));
+        assert!(charge_amount.0 <= ntoy(10));
+        emulator.update_context(bob(), ntoy(10));
+        emulator.contract.deposit_and_stake();
+        assert_eq!(
+            emulator.contract.internal_get_account(&bob()).stake_shares,
+            num_shares.0
+        );
+        assert_eq!(
+            emulator.contract.get_account_unstaked_balance(bob()).0,
+            ntoy(10) - charge_amount.0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ZERO_DEPOSIT")]
+    fn test_zero_deposit() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(alice(), 0);
+        emulator.contract.deposit();
+    }
+
+    fn add_farm_with_dates(emulator: &mut Emulator, start_date: u64, end_date: u64) {
+        emulator.update_context(bob(), 0);
+        emulator.contract.ft_on_transfer(
+            owner(),
+            U128(ntoy(100)),
+            json!({
+                "name": "test".to_string(),
+                "start_date": U64(start_date),
+                "end_date": U64(end_date),
+            })
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_farm_start_now() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.skip_epochs(1);
+        let now = emulator.block_timestamp;
+        add_farm_with_dates(&mut emulator, now, now + ONE_EPOCH_TS);
+        assert_eq!(emulator.contract.get_farm(0).start_date.0, now);
+
+        // Slightly in the past starts now. The first farm is stopped to reuse the name.
+        emulator.update_context(owner(), 0);
+        emulator.contract.stop_farm(0);
+        add_farm_with_dates(&mut emulator, now - 10_000_000_000, now + ONE_EPOCH_TS);
+        assert_eq!(emulator.contract.get_farm(1).start_date.0, now);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUPLICATE_FARM_NAME")]
+    fn test_farm_duplicate_name() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm_with_dates(&mut emulator, 0, ONE_EPOCH_TS * 4);
+        add_farm_with_dates(&mut emulator, 0, ONE_EPOCH_TS * 4);
+    }
+
+    #[test]
+    fn test_farm_name_reused_after_stop() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        add_farm_with_dates(&mut emulator, 0, ONE_EPOCH_TS * 4);
+        emulator.update_context(owner(), 0);
+        emulator.contract.stop_farm(0);
+        add_farm_with_dates(&mut emulator, 0, ONE_EPOCH_TS * 4);
+        assert_eq!(emulator.contract.get_farm(1).name, "test");
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FARM_TOO_EARLY")]
+    fn test_farm_start_in_the_past() {
+        let mut emulator = Emulator::new(
+            owner(),
+            "KuTCtARNzxZQ3YvXDeLjx83FDqxv2SdQTSbiq876zR7"
+                .parse()
+                .unwrap(),
+            zero_fee(),
+        );
+        emulator.update_context(owner(), 0);
+        emulator.contract.add_authorized_farm_token(&bob());
+        emulator.skip_epochs(1);
+        let now = emulator.block_timestamp;
+        add_farm_with_dates(&mut emulator, now - ONE_EPOCH_TS / 2, now + ONE_EPOCH_TS);
     }
 
     #[test]
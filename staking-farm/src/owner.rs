@@ -1,6 +1,11 @@
+use near_sdk::json_types::U64;
 use near_sdk::sys;
 use near_sdk::sys::{promise_batch_action_function_call, promise_batch_then};
 
+use crate::events;
+use crate::farm::MAX_DELEGATED_CLAIM_GAS;
+use crate::internal::{assert_reward_fee, assert_staking_key_curve, ZERO_ADDRESS};
+use crate::legacy::StakingContractV1;
 use crate::*;
 
 pub const OWNER_KEY: &[u8; 5] = b"OWNER";
@@ -10,6 +15,7 @@ const GET_CODE_METHOD_NAME: &[u8; 8] = b"get_code";
 const GET_CODE_GAS: Gas = Gas(50_000_000_000_000);
 const SELF_UPGRADE_METHOD_NAME: &[u8; 6] = b"update";
 const SELF_MIGRATE_METHOD_NAME: &[u8; 7] = b"migrate";
+const EMPTY_ARGS: &[u8; 2] = b"{}";
 const UPGRADE_GAS_LEFTOVER: Gas = Gas(5_000_000_000_000);
 const UPDATE_GAS_LEFTOVER: Gas = Gas(5_000_000_000_000);
 const NO_DEPOSIT: Balance = 0;
@@ -59,6 +65,7 @@ impl StakingContract {
     /// Updates current public key to the new given public key.
     pub fn update_staking_key(&mut self, stake_public_key: PublicKey) {
         self.assert_owner();
+        assert_staking_key_curve(&stake_public_key);
         // When updating the staking key, the contract has to restake.
         let _need_to_restake = self.internal_ping();
         self.stake_public_key = stake_public_key.into();
@@ -69,7 +76,7 @@ impl StakingContract {
     /// Updates current reward fee fraction to the new given fraction.
     pub fn update_reward_fee_fraction(&mut self, reward_fee_fraction: Ratio) {
         self.assert_owner();
-        reward_fee_fraction.assert_valid();
+        assert_reward_fee(&reward_fee_fraction);
 
         let need_to_restake = self.internal_ping();
         self.reward_fee_fraction.set(reward_fee_fraction);
@@ -78,11 +85,25 @@ impl StakingContract {
         }
     }
 
+    /// Owner's method.
+    /// Cancels the reward fee fraction change scheduled by `update_reward_fee_fraction`, while
+    /// it's not applied yet. The current reward fee fraction stays.
+    pub fn cancel_pending_reward_fee(&mut self) {
+        self.assert_owner();
+        let need_to_restake = self.internal_ping();
+        self.reward_fee_fraction.cancel();
+        if need_to_restake {
+            self.internal_restake();
+        }
+    }
+
     /// Can only be called by the factory.
     /// Decreases the current burn fee fraction to the new given fraction.
     pub fn decrease_burn_fee_fraction(&mut self, burn_fee_fraction: Ratio) {
         self.assert_factory();
         burn_fee_fraction.assert_valid();
+        // Zero numerator covers both the zero fee and the `0/0` ratio allowed at initialization.
+        assert_ne!(self.burn_fee_fraction.numerator, 0, "ERR_BURN_FEE_ALREADY_ZERO");
         assert!(
             u64::from(burn_fee_fraction.numerator) * u64::from(self.burn_fee_fraction.denominator)
                 < u64::from(burn_fee_fraction.denominator)
@@ -119,6 +140,119 @@ impl StakingContract {
         self.internal_restake();
     }
 
+    /// Owner's or authorized user's method.
+    /// Distributes rewards and always restakes, unless staking is paused.
+    /// Unlike `ping`, doesn't depend on whether the epoch changed, so can be used for maintenance.
+    /// Returns the new total staked balance.
+    pub fn reconcile(&mut self) -> U128 {
+        self.assert_owner_or_authorized_user();
+        self.internal_ping();
+        self.internal_restake();
+        self.total_staked_balance.into()
+    }
+
+    /// Owner's method.
+    /// Sends the surplus of the guarantee fund to `receiver_id`. The balance tracked by the pool
+    /// backs the staked balance of all the shares, the unstaked balances of all the accounts and
+    /// the configured guarantee fund, only the rounding surplus above all of them is sent.
+    /// The shares minted at the initialization stay unowned to keep the share price from being
+    /// inflated, and the staked and unstaked balances of all the accounts don't change.
+    /// Not available for the pools migrated from the previous versions, where the total unstaked
+    /// balance is unknown.
+    /// Returns the amount sent.
+    pub fn skim_guarantee_surplus(&mut self, receiver_id: AccountId) -> U128 {
+        self.assert_owner();
+        assert_ne!(receiver_id.as_str(), ZERO_ADDRESS, "ERR_INVALID_RECEIVER");
+        if self.internal_ping() {
+            self.internal_restake();
+        }
+        let total_unstaked_balance = self
+            .total_unstaked_balance
+            .expect("ERR_TOTAL_UNSTAKED_UNKNOWN");
+        let staked_balance = self.staked_amount_from_num_shares_rounded_up(self.total_stake_shares);
+        let reserved_balance = staked_balance + total_unstaked_balance + self.guarantee_fund;
+        let amount = self.last_total_balance.saturating_sub(reserved_balance);
+        assert!(amount > 0, "ERR_NO_SURPLUS");
+        self.last_total_balance -= amount;
+        env::log_str(&format!("Sending guarantee surplus of {} to @{}", amount, receiver_id));
+        Promise::new(receiver_id).transfer(amount);
+        amount.into()
+    }
+
+    /// Owner's method.
+    /// Sets the description, website and logo of the pool. Not given fields are cleared.
+    pub fn set_pool_metadata(
+        &mut self,
+        description: Option<String>,
+        website: Option<String>,
+        logo_url: Option<String>,
+    ) {
+        self.assert_owner();
+        let pool_metadata = PoolMetadata {
+            description,
+            website,
+            logo_url,
+        };
+        pool_metadata.assert_valid();
+        self.pool_metadata = pool_metadata;
+    }
+
+    /// Owner's method.
+    /// Sets gas for transferring the given farm token and resolving the transfer, used by claims
+    /// and stopping farms. Needed for the tokens with expensive `ft_transfer`.
+    pub fn set_farm_token_gas(
+        &mut self,
+        token_id: AccountId,
+        transfer_gas: U64,
+        resolve_gas: U64,
+    ) {
+        self.assert_owner();
+        assert!(transfer_gas.0 > 0 && resolve_gas.0 > 0, "ERR_ZERO_GAS");
+        self.farm_token_gas.insert(
+            &token_id,
+            &FarmTokenGas {
+                transfer_gas: Gas(transfer_gas.0),
+                resolve_gas: Gas(resolve_gas.0),
+            },
+        );
+    }
+
+    /// Owner's method.
+    /// Sets gas for calling `get_owner_account_id` of the delegator in the delegated claim and
+    /// the gas left after it. Needed for the delegators with expensive `get_owner_account_id`.
+    /// Both together are limited by `MAX_DELEGATED_CLAIM_GAS`.
+    pub fn set_delegated_claim_gas(&mut self, get_owner_gas: U64, gas_leftovers: U64) {
+        self.assert_owner();
+        assert!(get_owner_gas.0 > 0 && gas_leftovers.0 > 0, "ERR_ZERO_GAS");
+        assert!(
+            get_owner_gas.0 <= MAX_DELEGATED_CLAIM_GAS.0
+                && gas_leftovers.0 <= MAX_DELEGATED_CLAIM_GAS.0 - get_owner_gas.0,
+            "ERR_GAS_TOO_HIGH"
+        );
+        self.delegated_claim_gas = DelegatedClaimGas {
+            get_owner_gas: Gas(get_owner_gas.0),
+            gas_leftovers: Gas(gas_leftovers.0),
+        };
+    }
+
+    /// Owner's method.
+    /// Sets the wrapped NEAR token, that can be transferred to the pool with "stake" message to
+    /// unwrap and stake it. `None` disables it.
+    pub fn set_wnear_token_id(&mut self, token_id: Option<AccountId>) {
+        self.assert_owner();
+        self.wnear_token_id = token_id;
+    }
+
+    /// Owner's method.
+    /// Sets whether farms can only be created with the authorized farm tokens (default).
+    /// When disabled, any token transferred by the owner or an authorized user creates a farm.
+    /// The pool doesn't check such tokens, so a malicious or broken token contract can make the
+    /// claims of its farm fail or cost more gas.
+    pub fn set_require_token_allowlist(&mut self, required: bool) {
+        self.assert_owner();
+        self.require_token_allowlist = required;
+    }
+
     /// Add authorized user to the current contract.
     pub fn add_authorized_user(&mut self, account_id: AccountId) {
         self.assert_owner();
@@ -132,15 +266,17 @@ impl StakingContract {
     }
 
     /// Add authorized token.
-    pub fn add_authorized_farm_token(&mut self, token_id: &AccountId) {
+    /// Returns false if the token was already authorized.
+    pub fn add_authorized_farm_token(&mut self, token_id: &AccountId) -> bool {
         self.assert_owner_or_authorized_user();
-        self.authorized_farm_tokens.insert(&token_id);
+        self.authorized_farm_tokens.insert(&token_id)
     }
 
     /// Remove authorized token.
-    pub fn remove_authorized_farm_token(&mut self, token_id: &AccountId) {
+    /// Returns false if the token wasn't authorized.
+    pub fn remove_authorized_farm_token(&mut self, token_id: &AccountId) -> bool {
         self.assert_owner_or_authorized_user();
-        self.authorized_farm_tokens.remove(&token_id);
+        self.authorized_farm_tokens.remove(&token_id)
     }
 
     /// Asserts that the method was called by the owner.
@@ -175,8 +311,8 @@ impl StakingContract {
 }
 
 /// Upgrade method.
-/// Takes `hash` as an argument.
-/// Calls `factory_id.get_code(hash)` first to get the code.
+/// Takes `code_hash` as an argument, upgrades to the latest code of the factory if not given.
+/// Calls `factory_id.get_code(code_hash)` first to get the code.
 /// Callback to `self.update(code)` to upgrade code.
 /// Callback after that to `self.migrate()` to migrate the state using new code.
 #[no_mangle]
@@ -194,6 +330,10 @@ pub extern "C" fn upgrade() {
     unsafe {
         // Load hash to the register 0.
         sys::input(0);
+        if sys::register_len(0) == 0 {
+            // No arguments, asking the factory for the latest code.
+            sys::write_register(0, EMPTY_ARGS.len() as _, EMPTY_ARGS.as_ptr() as _);
+        }
         // Create a promise for factory contract.
         let promise_id = sys::promise_batch_create(
             factory_id.as_bytes().len() as _,
@@ -268,7 +408,7 @@ pub extern "C" fn update() {
     }
 }
 
-/// Empty migrate method for future use.
+/// Migrates the state from the previous version.
 /// Makes sure that state version is previous.
 /// When updating code, make sure to update what previous version actually is.
 #[no_mangle]
@@ -283,9 +423,17 @@ pub extern "C" fn migrate() {
 
     // Check that state version is previous.
     // Will fail migration in the case of trying to skip the versions.
-    assert_eq!(
-        StakingContract::internal_get_state_version(),
-        "staking-farm:1.0.0"
+    // 1.0.0 and 1.1.0 have the same state.
+    let state_version = StakingContract::internal_get_state_version();
+    assert!(
+        state_version == "staking-farm:1.0.0" || state_version == "staking-farm:1.1.0",
+        "Can't migrate from {}",
+        state_version
     );
+    let old_state: StakingContractV1 = env::state_read().expect("MUST HAVE STATE");
+    let mut state = StakingContract::from(old_state);
+    state.last_upgrade_epoch = Some(env::epoch_height());
+    env::state_write(&state);
     StakingContract::internal_set_version();
+    events::emit_contract_upgraded(&state_version, &StakingContract::internal_get_version());
 }
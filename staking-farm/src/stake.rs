@@ -1,3 +1,7 @@
+use near_sdk::is_promise_success;
+use near_sdk::json_types::U64;
+
+use crate::events;
 use crate::internal::{MIN_BURN_AMOUNT, ZERO_ADDRESS};
 use crate::*;
 
@@ -7,18 +11,27 @@ pub trait SelfContract {
     /// A callback to check the result of the staking action.
     /// In case the stake amount is less than the minimum staking threshold, the staking action
     /// fails, and the stake amount is not changed. This might lead to inconsistent state and the
-    /// follow withdraw calls might fail. To mitigate this, the contract will issue a new unstaking
-    /// action in case of the failure of the first staking action.
+    /// follow withdraw calls might fail. To mitigate this, the contract will retry staking the
+    /// current total staked balance once in case of the failure of the first staking action.
     fn on_stake_action(&mut self);
 
+    /// A callback to check the result of the retried staking action.
+    /// If the retry failed too, the contract issues a new unstaking action.
+    fn on_retry_stake_action(&mut self);
+
     /// Check if reward withdrawal succeeded and if it failed, refund reward back to the user.
     fn callback_post_withdraw_reward(
         &mut self,
         token_id: AccountId,
         sender_id: AccountId,
         amount: U128,
+        farm_id: Option<u64>,
     );
 
+    /// Check if returning leftover of the stopped farm succeeded and if it failed, return
+    /// leftover back to the farm.
+    fn callback_post_stop_farm(&mut self, farm_id: u64, amount: U128, end_date: U64);
+
     /// Callback after getting the owner of the given account.
     fn callback_post_get_owner(
         &mut self,
@@ -26,6 +39,37 @@ pub trait SelfContract {
         delegator_id: AccountId,
         account_id: AccountId,
     ) -> Promise;
+
+    /// Check if unwrapping wNEAR succeeded and stake the received NEAR for the account.
+    /// Returns the amount of wNEAR to refund.
+    fn callback_post_unwrap(&mut self, account_id: AccountId, amount: U128) -> U128;
+
+    /// Callback after getting the storage balance of the `claim_and_register` caller.
+    fn callback_post_storage_balance(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        deposit: U128,
+    );
+
+    /// Callback after getting the storage bounds of the token for the `claim_and_register` caller.
+    fn callback_post_storage_bounds(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        deposit: U128,
+    );
+
+    /// Check if registering the `claim_and_register` caller succeeded, then transfer the reward.
+    fn callback_post_storage_deposit(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+        storage_deposit: U128,
+    );
 }
 
 #[near_bindgen]
@@ -48,7 +92,7 @@ impl StakingContract {
         self.internal_ping();
 
         let amount = self.internal_deposit();
-        self.internal_stake(amount);
+        self.internal_stake(&env::predecessor_account_id(), amount);
 
         self.internal_restake();
     }
@@ -56,24 +100,21 @@ impl StakingContract {
     /// Withdraws the entire unstaked balance from the predecessor account.
     /// It's only allowed if the `unstake` action was not performed in the four most recent epochs.
     pub fn withdraw_all(&mut self) {
-        let need_to_restake = self.internal_ping();
-
-        let account_id = env::predecessor_account_id();
-        let account = self.internal_get_account(&account_id);
-        self.internal_withdraw(&account_id, account.unstaked);
-
-        if need_to_restake {
-            self.internal_restake();
-        }
+        self.withdraw(None);
     }
 
     /// Withdraws the non staked balance for given account.
+    /// If `amount` is not given, withdraws the entire unstaked balance.
     /// It's only allowed if the `unstake` action was not performed in the four most recent epochs.
-    pub fn withdraw(&mut self, amount: U128) {
+    pub fn withdraw(&mut self, amount: Option<U128>) {
         let need_to_restake = self.internal_ping();
 
-        let amount: Balance = amount.into();
-        self.internal_withdraw(&env::predecessor_account_id(), amount);
+        let account_id = env::predecessor_account_id();
+        let amount: Balance = match amount {
+            Some(amount) => amount.into(),
+            None => self.internal_get_account(&account_id).unstaked,
+        };
+        self.internal_withdraw(&account_id, amount);
 
         if need_to_restake {
             self.internal_restake();
@@ -87,7 +128,7 @@ impl StakingContract {
 
         let account_id = env::predecessor_account_id();
         let account = self.internal_get_account(&account_id);
-        self.internal_stake(account.unstaked);
+        self.internal_stake(&account_id, account.unstaked);
 
         self.internal_restake();
     }
@@ -99,7 +140,7 @@ impl StakingContract {
         self.internal_ping();
 
         let amount: Balance = amount.into();
-        self.internal_stake(amount);
+        self.internal_stake(&env::predecessor_account_id(), amount);
 
         self.internal_restake();
     }
@@ -110,6 +151,29 @@ impl StakingContract {
         self.internal_unstake_all(&env::predecessor_account_id());
     }
 
+    /// Withdraws the unstaked balance of the predecessor that is already available and unstakes
+    /// all the staked balance. The newly unstaked balance will be available for withdrawal in
+    /// four epochs.
+    pub fn unstake_all_and_queue_withdraw(&mut self) {
+        let need_to_restake = self.internal_ping();
+
+        let account_id = env::predecessor_account_id();
+        let account = self.internal_get_account(&account_id);
+        // Unstaking locks the whole unstaked balance again, so the available balance is
+        // withdrawn first.
+        if account.unstaked > 0 && account.unstaked_available_epoch_height <= env::epoch_height() {
+            self.internal_withdraw(&account_id, account.unstaked);
+        }
+        let amount = self.staked_amount_from_num_shares_rounded_down(account.stake_shares);
+        if amount > 0 {
+            self.inner_unstake(&account_id, amount);
+        }
+
+        if amount > 0 || need_to_restake {
+            self.internal_restake();
+        }
+    }
+
     /// Unstakes the given amount from the inner account of the predecessor.
     /// The inner account should have enough staked balance.
     /// The new total unstaked balance will be available for withdrawal in four epochs.
@@ -123,7 +187,21 @@ impl StakingContract {
         self.internal_restake();
     }
 
+    /// Unstakes the given number of "stake" shares from the inner account of the predecessor.
+    /// The inner account should have enough "stake" shares.
+    /// The new total unstaked balance will be available for withdrawal in four epochs.
+    pub fn unstake_shares(&mut self, shares: U128) {
+        // Unstake action always restakes
+        self.internal_ping();
+
+        self.inner_unstake_shares(&env::predecessor_account_id(), shares.into());
+
+        self.internal_restake();
+    }
+
     /// Unstakes all the tokens that must be burnt.
+    /// Does nothing if there is nothing new to unstake, to not delay the unlock of the previously
+    /// unstaked tokens.
     pub fn unstake_burn(&mut self) {
         self.internal_unstake_all(&AccountId::new_unchecked(ZERO_ADDRESS.to_string()));
     }
@@ -158,11 +236,69 @@ impl StakingContract {
             PromiseResult::Successful(_) => true,
             _ => false,
         };
+        if stake_action_succeeded {
+            return;
+        }
+
+        // The failure might be transient, e.g. the balance changed before the stake action, so
+        // retry once with the current amount before dropping out of the validation.
+        // The protocol minimum stake isn't available to the contract, so the retry can't be
+        // checked against it.
+        let stake_amount = self.internal_restake_amount();
+        if !self.paused && stake_amount > 0 {
+            env::log_str(&format!("Stake action failed, retrying to stake {}", stake_amount));
+            Promise::new(env::current_account_id())
+                .stake(stake_amount, self.stake_public_key.clone())
+                .then(ext_self::on_retry_stake_action(
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    ON_STAKE_ACTION_GAS,
+                ));
+        } else if env::account_locked_balance() > 0 {
+            Promise::new(env::current_account_id()).stake(0, self.stake_public_key.clone());
+        }
+    }
 
-        // If the stake action failed and the current locked amount is positive, then the contract
-        // has to unstake.
-        if !stake_action_succeeded && env::account_locked_balance() > 0 {
+    #[private]
+    pub fn on_retry_stake_action(&mut self) {
+        // If the retried stake action failed and the current locked amount is positive, then the
+        // contract has to unstake.
+        if !is_promise_success() && env::account_locked_balance() > 0 {
             Promise::new(env::current_account_id()).stake(0, self.stake_public_key.clone());
         }
     }
+
+    /// Callback after unwrapping wNEAR transferred to stake.
+    /// If unwrapping failed, the wNEAR are still on the pool and returned to the sender as unused.
+    /// Otherwise the received NEAR are deposited to the account and staked.
+    #[private]
+    pub fn callback_post_unwrap(&mut self, account_id: AccountId, amount: U128) -> U128 {
+        let amount: Balance = amount.into();
+        if !is_promise_success() {
+            // The NEAR never arrived.
+            self.pending_unwrap_balance -= amount;
+            env::log_str(&format!("Failed to unwrap {} for @{}", amount, account_id));
+            return U128(amount);
+        }
+        self.internal_ping();
+
+        // The NEAR has arrived before the callback and is excluded from the rewards by the ping.
+        self.pending_unwrap_balance -= amount;
+        self.last_total_balance += amount;
+        let mut account = self.internal_get_account(&account_id);
+        account.unstaked += amount;
+        self.internal_save_account(&account_id, &account);
+        self.internal_add_total_unstaked(amount);
+        events::emit_deposit(&account_id, amount, account.unstaked);
+        // The amount too small to buy a share stays unstaked, instead of failing the callback.
+        // Same if the gas doesn't cover the farms, the account can stake it later.
+        if self.num_shares_from_staked_amount_rounded_down(amount) > 0
+            && self.internal_has_distribution_gas()
+        {
+            self.internal_stake(&account_id, amount);
+        }
+
+        self.internal_restake();
+        U128(0)
+    }
 }
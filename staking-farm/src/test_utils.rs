@@ -67,6 +67,8 @@ pub mod tests {
         pub locked_amount: Balance,
         last_total_staked_balance: Balance,
         last_total_stake_shares: Balance,
+        /// Shares minted at the initialization, unknown for the migrated pools.
+        pub unowned_stake_shares: Option<NumStakeShares>,
         pub context: VMContext,
     }
 
@@ -96,6 +98,10 @@ pub mod tests {
                     numerator: 0,
                     denominator: 0,
                 },
+                None,
+                None,
+                None,
+                None,
             );
             let last_total_staked_balance = contract.total_staked_balance;
             let last_total_stake_shares = contract.total_stake_shares;
@@ -108,6 +114,7 @@ pub mod tests {
                 locked_amount: 0,
                 last_total_staked_balance,
                 last_total_stake_shares,
+                unowned_stake_shares: Some(last_total_stake_shares),
                 context,
             }
         }
@@ -122,6 +129,8 @@ pub mod tests {
             );
             self.last_total_staked_balance = total_staked_balance;
             self.last_total_stake_shares = total_stake_shares;
+            #[cfg(feature = "test-invariants")]
+            self.contract.assert_invariants(self.unowned_stake_shares);
         }
 
         pub fn update_context(&mut self, predecessor_account_id: AccountId, deposit: Balance) {
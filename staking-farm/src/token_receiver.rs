@@ -3,10 +3,25 @@ use near_sdk::{env, serde_json, PromiseOrValue};
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 
+use crate::farm::RewardCurve;
+use crate::stake::ext_self;
 use crate::*;
 
 const ERR_MSG_REQUIRED_FIELD: &str = "ERR_MSG_REQUIRED_FIELD";
 
+/// Message of the wNEAR transfer to unwrap and stake it for the sender.
+const STAKE_MSG: &str = "stake";
+/// Gas for unwrapping wNEAR, including the transfer of NEAR back.
+const GAS_FOR_NEAR_WITHDRAW: Gas = Gas(10_000_000_000_000);
+/// Gas for the callback after unwrapping, covers the distribution of all active farms and restake.
+const GAS_FOR_CALLBACK_POST_UNWRAP: Gas = Gas(60_000_000_000_000);
+
+/// Interface of the wrapped NEAR contract.
+#[ext_contract(ext_wnear)]
+pub trait WrappedNear {
+    fn near_withdraw(&mut self, amount: U128);
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct FarmingDetails {
@@ -19,6 +34,9 @@ pub struct FarmingDetails {
     pub end_date: U64,
     /// Existing farm ID.
     pub farm_id: Option<u64>,
+    /// Distribution curve of the new farm, linear if not given.
+    /// Existing farm keeps its curve.
+    pub curve: Option<RewardCurve>,
 }
 
 #[near_bindgen]
@@ -26,15 +44,28 @@ impl FungibleTokenReceiver for StakingContract {
     /// Callback on receiving tokens by this contract.
     /// transfer reward token with specific msg indicate
     /// which farm to be deposited to.
+    /// If the farm can't be created or changed (e.g. too many active farms), this method panics
+    /// and the token contract returns the full amount to the sender in `ft_resolve_transfer`.
+    /// With `msg` equal to "stake", the wNEAR set by the owner is unwrapped and staked for the
+    /// sender. Other tokens with this message are returned as unused.
     fn ft_on_transfer(
         &mut self,
         sender_id: AccountId,
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
+        if msg == STAKE_MSG {
+            if self.wnear_token_id.as_ref() != Some(&env::predecessor_account_id()) {
+                env::log_str("ERR_NOT_WNEAR_TOKEN");
+                return PromiseOrValue::Value(amount);
+            }
+            return self.internal_unwrap_and_stake(sender_id, amount.0).into();
+        }
         assert!(
-            self.authorized_farm_tokens
-                .contains(&env::predecessor_account_id()),
+            !self.require_token_allowlist
+                || self
+                    .authorized_farm_tokens
+                    .contains(&env::predecessor_account_id()),
             "ERR_NOT_AUTHORIZED_TOKEN"
         );
         assert!(
@@ -53,17 +84,44 @@ impl FungibleTokenReceiver for StakingContract {
             );
         } else {
             assert!(
-                self.active_farms.len() <= MAX_NUM_ACTIVE_FARMS,
+                self.active_farms.len() < MAX_NUM_ACTIVE_FARMS,
                 "ERR_TOO_MANY_ACTIVE_FARMS"
             );
             self.internal_deposit_farm_tokens(
                 &env::predecessor_account_id(),
+                &sender_id,
                 message.name.expect(ERR_MSG_REQUIRED_FIELD),
                 amount.0,
                 message.start_date.expect(ERR_MSG_REQUIRED_FIELD).0,
                 message.end_date.0,
+                message.curve.unwrap_or_default(),
             );
         }
         PromiseOrValue::Value(U128(0))
     }
 }
+
+impl StakingContract {
+    /// Unwraps the received wNEAR to stake the NEAR for the account in the callback.
+    fn internal_unwrap_and_stake(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        assert!(amount > 0, "ERR_ZERO_DEPOSIT");
+        if self.internal_ping() {
+            self.internal_restake();
+        }
+        // The NEAR may arrive before the callback, so it's excluded from the rewards until then.
+        self.pending_unwrap_balance += amount;
+        ext_wnear::near_withdraw(
+            U128(amount),
+            env::predecessor_account_id(),
+            1,
+            GAS_FOR_NEAR_WITHDRAW,
+        )
+        .then(ext_self::callback_post_unwrap(
+            account_id,
+            U128(amount),
+            env::current_account_id(),
+            0,
+            GAS_FOR_CALLBACK_POST_UNWRAP,
+        ))
+    }
+}
@@ -1,10 +1,14 @@
 use near_sdk::json_types::{U128, U64};
 use near_sdk::{env, AccountId};
 
+use crate::farm::{FarmStatus, RewardCurve};
 use crate::internal::ZERO_ADDRESS;
 use crate::Farm;
 use crate::*;
 
+/// Maximum number of accounts requested at once by the batch views.
+const MAX_BATCH_ACCOUNTS: usize = 100;
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct HumanReadableFarm {
@@ -15,11 +19,25 @@ pub struct HumanReadableFarm {
     pub start_date: U64,
     pub end_date: U64,
     pub active: bool,
+    /// Whether the farm is pending, running or ended at the current block time.
+    pub status: FarmStatus,
+    /// Number of seconds until the end of the farm, 0 for the ended farms.
+    pub seconds_remaining: U64,
+    pub curve: RewardCurve,
+    /// Reward distributed every second, the average for non-linear curves.
+    pub reward_per_second: U128,
+    /// Account that funded the farm. Unknown for the old farms.
+    pub creator: Option<AccountId>,
+    /// Block timestamp when the farm was created. Unknown for the old farms.
+    pub created_at: Option<U64>,
 }
 
 impl HumanReadableFarm {
     fn from(farm_id: u64, farm: Farm) -> Self {
         let active = farm.is_active();
+        let status = farm.status(env::block_timestamp());
+        let seconds_remaining = U64(farm.seconds_remaining(env::block_timestamp()));
+        let reward_per_second = U128(farm.reward_per_session());
         HumanReadableFarm {
             farm_id,
             name: farm.name,
@@ -28,6 +46,12 @@ impl HumanReadableFarm {
             start_date: U64(farm.start_date),
             end_date: U64(farm.end_date),
             active,
+            status,
+            seconds_remaining,
+            curve: farm.curve,
+            reward_per_second,
+            creator: farm.creator,
+            created_at: farm.created_at.map(U64),
         }
     }
 }
@@ -45,6 +69,33 @@ pub struct HumanReadableAccount {
     pub can_withdraw: bool,
 }
 
+/// Unclaimed reward of the account from a single farm.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountFarmReward {
+    pub farm_id: u64,
+    pub token_id: AccountId,
+    /// Reward from this farm that is not claimed yet.
+    pub unclaimed: U128,
+}
+
+/// Represents all the staking and farming details of the account.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountDetails {
+    pub account_id: AccountId,
+    /// The unstaked balance that can be withdrawn or staked.
+    pub unstaked_balance: U128,
+    /// The amount balance staked at the current "stake" share price.
+    pub staked_balance: U128,
+    /// Whether the unstaked balance is available for withdrawal now.
+    pub can_withdraw: bool,
+    /// The epoch height from which the unstaked balance can be withdrawn.
+    pub unstaked_available_epoch_height: U64,
+    /// Unclaimed rewards from the active farms.
+    pub farms: Vec<AccountFarmReward>,
+}
+
 /// Represents pool summary with all farms and rates applied.
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -63,6 +114,50 @@ pub struct PoolSummary {
     pub burn_fee_fraction: Ratio,
     /// Active farms that affect stakers.
     pub farms: Vec<HumanReadableFarm>,
+    /// The public key used for staking.
+    pub stake_public_key: PublicKey,
+    /// Current contract version.
+    pub version: String,
+    /// Whether the staking is paused.
+    pub paused: bool,
+    /// Description of the pool.
+    pub metadata: PoolMetadata,
+    /// The epoch height when the pool was initialized.
+    pub created_epoch: U64,
+}
+
+/// Represents current and upcoming fees of the pool.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeSchedule {
+    /// The fraction of the reward that currently goes to the owner.
+    pub current_reward_fee: Ratio,
+    /// The reward fee fraction after `reward_fee_effective_epoch`.
+    pub next_reward_fee: Ratio,
+    /// Epoch height from which the next reward fee applies.
+    pub reward_fee_effective_epoch: U64,
+    /// The fraction of the reward that gets burnt. Changes take effect immediately.
+    pub burn_fee: Ratio,
+}
+
+/// Represents the configuration of the pool, that wallets need on load.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    /// Pool owner.
+    pub owner_id: AccountId,
+    /// Factory that created the pool and provides the code for upgrades.
+    pub factory_id: AccountId,
+    /// Current contract version.
+    pub version: String,
+    /// Whether the staking is paused.
+    pub paused: bool,
+    /// The fraction of the reward that goes to the owner of the staking pool.
+    pub reward_fee_fraction: Ratio,
+    /// The fraction of the reward that gets burnt.
+    pub burn_fee_fraction: Ratio,
+    /// The number of epochs after unstaking before the balance can be withdrawn.
+    pub num_epochs_to_unlock: U64,
 }
 
 #[near_bindgen]
@@ -81,9 +176,52 @@ impl StakingContract {
             next_reward_fee_fraction: self.reward_fee_fraction.next().clone(),
             burn_fee_fraction: self.burn_fee_fraction.clone(),
             farms: self.get_active_farms(),
+            stake_public_key: self.get_staking_key(),
+            version: Self::internal_get_version(),
+            paused: self.paused,
+            metadata: self.pool_metadata.clone(),
+            created_epoch: self.created_epoch.into(),
         }
     }
 
+    /// Returns the total rewards received by the pool in the most recent epochs, oldest first.
+    /// Epochs without rewards are skipped.
+    pub fn get_recent_rewards(&self) -> Vec<(U64, U128)> {
+        self.recent_rewards
+            .iter()
+            .map(|&(epoch_height, reward)| (U64(epoch_height), U128(reward)))
+            .collect()
+    }
+
+    /// Returns the epoch height when the pool was initialized.
+    pub fn get_created_epoch(&self) -> U64 {
+        self.created_epoch.into()
+    }
+
+    /// Returns the epoch height of the last code upgrade, or `None` if the pool was never upgraded
+    /// since the upgrades started to be recorded.
+    pub fn get_last_upgrade_epoch(&self) -> Option<U64> {
+        self.last_upgrade_epoch.map(U64)
+    }
+
+    /// Returns the configuration of the pool in one call.
+    pub fn get_contract_config(&self) -> ContractConfig {
+        ContractConfig {
+            owner_id: Self::internal_get_owner_id(),
+            factory_id: Self::internal_get_factory_id(),
+            version: Self::internal_get_version(),
+            paused: self.paused,
+            reward_fee_fraction: self.reward_fee_fraction.current().clone(),
+            burn_fee_fraction: self.burn_fee_fraction.clone(),
+            num_epochs_to_unlock: NUM_EPOCHS_TO_UNLOCK.into(),
+        }
+    }
+
+    /// Returns the description of the pool set by the owner.
+    pub fn get_pool_metadata(&self) -> PoolMetadata {
+        self.pool_metadata.clone()
+    }
+
     ///
     /// OWNER
     ///
@@ -93,6 +231,12 @@ impl StakingContract {
         Self::internal_get_version()
     }
 
+    /// Returns the version of the contract state, set by the initialization or the last migration.
+    /// Differs from `get_version` if the code was updated, but the state wasn't migrated.
+    pub fn get_state_version(&self) -> String {
+        Self::internal_get_state_version()
+    }
+
     /// Returns current owner from the storage.
     pub fn get_owner_id(&self) -> AccountId {
         Self::internal_get_owner_id()
@@ -103,6 +247,32 @@ impl StakingContract {
         Self::internal_get_factory_id()
     }
 
+    /// Returns the total reward fee earned by the owner, valued at the current share price.
+    pub fn get_owner_fee_earned(&self) -> U128 {
+        self.staked_amount_from_num_shares_rounded_down(self.total_owner_fee_shares)
+            .into()
+    }
+
+    /// Returns gas for transferring the given farm token and resolving the transfer.
+    pub fn get_farm_token_gas(&self, token_id: AccountId) -> (U64, U64) {
+        let gas = self.internal_get_farm_token_gas(&token_id);
+        (gas.transfer_gas.0.into(), gas.resolve_gas.0.into())
+    }
+
+    /// Returns gas for calling `get_owner_account_id` of the delegator in the delegated claim and
+    /// the gas left after it.
+    pub fn get_delegated_claim_gas(&self) -> (U64, U64) {
+        (
+            self.delegated_claim_gas.get_owner_gas.0.into(),
+            self.delegated_claim_gas.gas_leftovers.0.into(),
+        )
+    }
+
+    /// Returns the wrapped NEAR token that can be transferred to the pool to stake.
+    pub fn get_wnear_token_id(&self) -> Option<AccountId> {
+        self.wnear_token_id.clone()
+    }
+
     /// Return all authorized users.
     pub fn get_authorized_users(&self) -> Vec<AccountId> {
         self.authorized_users.to_vec()
@@ -113,6 +283,11 @@ impl StakingContract {
         self.authorized_farm_tokens.to_vec()
     }
 
+    /// Returns whether farms can only be created with the authorized farm tokens.
+    pub fn get_require_token_allowlist(&self) -> bool {
+        self.require_token_allowlist
+    }
+
     ///
     /// FARMS
     ///
@@ -134,21 +309,86 @@ impl StakingContract {
         HumanReadableFarm::from(farm_id, self.internal_get_farm(farm_id))
     }
 
+    /// Returns the projected `(distributed, undistributed)` amounts of the farm at the given
+    /// timestamp, assuming no tokens are added. `distributed` is the amount given to the stakers
+    /// since the farm started or was last topped up.
+    /// Timestamps after the end of the farm are treated as its end.
+    pub fn preview_farm_distribution(&self, farm_id: u64, at_timestamp: U64) -> (U128, U128) {
+        let farm = self.internal_get_farm(farm_id);
+        assert!(at_timestamp.0 >= farm.start_date, "ERR_PREVIEW_BEFORE_START");
+        assert!(at_timestamp.0 >= env::block_timestamp(), "ERR_PREVIEW_IN_THE_PAST");
+        let timestamp = std::cmp::min(at_timestamp.0, farm.end_date);
+        let undistributed = self
+            .internal_calculate_distribution_at(
+                &farm,
+                self.total_stake_shares - self.total_burn_shares,
+                timestamp,
+            )
+            .map_or(farm.amount, |distribution| distribution.undistributed);
+        (U128(farm.amount - undistributed), U128(undistributed))
+    }
+
+    /// Returns total amount of the given token recorded to the accounts by all the farms.
+    /// Tokens returned to the account after a failed claim are still counted as distributed.
+    pub fn get_total_rewards_distributed(&self, token_id: AccountId) -> U128 {
+        U128(self.total_distributed.get(&token_id).unwrap_or(0))
+    }
+
     pub fn get_unclaimed_reward(&self, account_id: AccountId, farm_id: u64) -> U128 {
         if account_id == AccountId::new_unchecked(ZERO_ADDRESS.to_string()) {
             return U128(0);
         }
-        let account = self.accounts.get(&account_id).expect("ERR_NO_ACCOUNT");
+        let account = self.internal_get_account(&account_id);
         let mut farm = self.farms.get(farm_id).expect("ERR_NO_FARM");
         let (_rps, reward) = self.internal_unclaimed_balance(&account, farm_id, &mut farm);
         let prev_reward = *account.amounts.get(&farm.token_id).unwrap_or(&0);
         U128(reward + prev_reward)
     }
 
+    /// Returns the unclaimed rewards of the given accounts from the given farm in the same order.
+    pub fn get_unclaimed_rewards_batch(
+        &self,
+        account_ids: Vec<AccountId>,
+        farm_id: u64,
+    ) -> Vec<U128> {
+        assert!(
+            account_ids.len() <= MAX_BATCH_ACCOUNTS,
+            "ERR_TOO_MANY_ACCOUNTS"
+        );
+        account_ids
+            .into_iter()
+            .map(|account_id| self.get_unclaimed_reward(account_id, farm_id))
+            .collect()
+    }
+
+    /// Returns the amount of the given token that `claim` would transfer to the account now.
+    pub fn get_claimable(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        if account_id.as_str() == ZERO_ADDRESS {
+            return U128(0);
+        }
+        let account = self.internal_get_account(&account_id);
+        let farmed: Balance = self
+            .active_farms
+            .iter()
+            .map(|&farm_id| (farm_id, self.internal_get_farm(farm_id)))
+            .filter(|(_, farm)| farm.token_id == token_id)
+            .map(|(farm_id, mut farm)| {
+                self.internal_unclaimed_balance(&account, farm_id, &mut farm).1
+            })
+            .sum();
+        U128(farmed + *account.amounts.get(&token_id).unwrap_or(&0))
+    }
+
     ///
     /// ACCOUNT
     ///
 
+    /// Returns total amount of the given token claimed by the account.
+    pub fn get_account_total_claimed(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        let account = self.internal_get_account(&account_id);
+        U128(*account.total_claimed.get(&token_id).unwrap_or(&0))
+    }
+
     /// Returns the unstaked balance of the given account.
     pub fn get_account_unstaked_balance(&self, account_id: AccountId) -> U128 {
         self.get_account(account_id).unstaked_balance
@@ -161,22 +401,137 @@ impl StakingContract {
         self.get_account(account_id).staked_balance
     }
 
+    /// Returns the staked balance of the given account including the rewards of the current epoch
+    /// that are not yet distributed, because `ping` wasn't called yet.
+    /// NOTE: `get_account_staked_balance` returns the balance from the current state of the
+    /// contract, which is updated with the rewards only on `ping` or any other staking action.
+    /// The owner and the burn account also get the shares of the fees taken from these rewards.
+    pub fn get_account_staked_balance_projected(&self, account_id: AccountId) -> U128 {
+        let account = self.internal_get_account(&account_id);
+        let (total_staked_balance, total_stake_shares, num_burn_shares, num_owner_shares) =
+            self.internal_projected_totals();
+        let mut stake_shares = account.stake_shares;
+        if account_id.as_str() == ZERO_ADDRESS {
+            stake_shares += num_burn_shares;
+        }
+        if account_id == Self::internal_get_owner_id() {
+            stake_shares += num_owner_shares;
+        }
+        (U256::from(total_staked_balance) * U256::from(stake_shares)
+            / U256::from(total_stake_shares))
+        .as_u128()
+        .into()
+    }
+
+    /// Returns the number of "stake" shares of the given account, 0 for an unknown account.
+    /// Together with `get_total_shares` gives the account's part of the pool.
+    pub fn get_account_shares(&self, account_id: AccountId) -> U128 {
+        self.internal_get_account(&account_id).stake_shares.into()
+    }
+
+    /// Returns the total number of "stake" shares, including the ones not owned by any account.
+    pub fn get_total_shares(&self) -> U128 {
+        self.total_stake_shares.into()
+    }
+
     /// Returns the total balance of the given account (including staked and unstaked balances).
     pub fn get_account_total_balance(&self, account_id: AccountId) -> U128 {
         let account = self.get_account(account_id);
         (account.unstaked_balance.0 + account.staked_balance.0).into()
     }
 
+    /// Returns the total balances of the given accounts in the same order.
+    /// Unknown accounts have zero balance.
+    pub fn get_accounts_total_balance(&self, account_ids: Vec<AccountId>) -> Vec<U128> {
+        assert!(
+            account_ids.len() <= MAX_BATCH_ACCOUNTS,
+            "ERR_TOO_MANY_ACCOUNTS"
+        );
+        account_ids
+            .into_iter()
+            .map(|account_id| self.get_account_total_balance(account_id))
+            .collect()
+    }
+
     /// Returns `true` if the given account can withdraw tokens in the current epoch.
     pub fn is_account_unstaked_balance_available(&self, account_id: AccountId) -> bool {
         self.get_account(account_id).can_withdraw
     }
 
+    /// Returns the epoch height from which the unstaked balance of the given account can be
+    /// withdrawn, or 0 if the account has no unstaked balance.
+    pub fn get_account_unlock_epoch(&self, account_id: AccountId) -> U64 {
+        let account = self.internal_get_account(&account_id);
+        if account.unstaked == 0 {
+            return U64(0);
+        }
+        account.unstaked_available_epoch_height.into()
+    }
+
     /// Returns the total staking balance.
     pub fn get_total_staked_balance(&self) -> U128 {
         self.total_staked_balance.into()
     }
 
+    /// Returns the number of "stake" shares bought with the burn fee, that are not burnt yet.
+    pub fn get_burn_shares(&self) -> U128 {
+        self.total_burn_shares.into()
+    }
+
+    /// Returns the staked balance of the burn shares at the current share price.
+    pub fn get_burn_staked_balance(&self) -> U128 {
+        self.staked_amount_from_num_shares_rounded_down(self.total_burn_shares)
+            .into()
+    }
+
+    /// Returns the number of "stake" shares the given amount would buy at the current share price
+    /// and the amount that would be charged from the unstaked balance for them.
+    /// Before anyone stakes, the initial balance is backed by the same number of shares, so the
+    /// price starts at 1.
+    pub fn preview_stake(&self, amount: U128) -> (U128, U128) {
+        let num_shares = self.num_shares_from_staked_amount_rounded_down(amount.0);
+        let charge_amount = self.staked_amount_from_num_shares_rounded_down(num_shares);
+        (num_shares.into(), charge_amount.into())
+    }
+
+    /// Returns the total unstaked balance of all accounts, which the pool must keep liquid to
+    /// honor the withdrawals.
+    /// Returns `None` for the pools migrated from the previous versions, where the total is unknown,
+    /// use `get_accounts` to sum it up instead.
+    pub fn get_total_unstaked_balance(&self) -> Option<U128> {
+        self.total_unstaked_balance.map(U128)
+    }
+
+    /// Returns the total balance (staked and unstaked) recorded by the contract at the last ping.
+    /// The rewards are computed from the growth of the account balance over this value.
+    pub fn get_last_total_balance(&self) -> U128 {
+        self.last_total_balance.into()
+    }
+
+    /// Returns the balance locked for staking by the protocol.
+    pub fn get_protocol_locked_balance(&self) -> U128 {
+        env::account_locked_balance().into()
+    }
+
+    /// Returns the total staked balance recorded by the contract and the balance locked by the
+    /// protocol. They differ while the stake action is in flight or after it failed.
+    pub fn get_stake_discrepancy(&self) -> (U128, U128) {
+        (
+            self.total_staked_balance.into(),
+            env::account_locked_balance().into(),
+        )
+    }
+
+    /// Returns the current fees and when the next reward fee applies.
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        FeeSchedule {
+            current_reward_fee: self.reward_fee_fraction.current().clone(),
+            next_reward_fee: self.reward_fee_fraction.next().clone(),
+            reward_fee_effective_epoch: self.reward_fee_fraction.available_epoch_height().into(),
+            burn_fee: self.burn_fee_fraction.clone(),
+        }
+    }
+
     /// Returns the current reward fee as a fraction.
     pub fn get_reward_fee_fraction(&self) -> Ratio {
         self.reward_fee_fraction.current().clone()
@@ -205,6 +560,34 @@ impl StakingContract {
         }
     }
 
+    /// Returns balances, unlock epoch and unclaimed farm rewards of the account in one call.
+    pub fn get_account_staking_details(&self, account_id: AccountId) -> AccountDetails {
+        let account = self.internal_get_account(&account_id);
+        let farms = self
+            .active_farms
+            .iter()
+            .map(|&farm_id| {
+                let mut farm = self.internal_get_farm(farm_id);
+                let (_rps, reward) = self.internal_unclaimed_balance(&account, farm_id, &mut farm);
+                AccountFarmReward {
+                    farm_id,
+                    token_id: farm.token_id,
+                    unclaimed: U128(reward + *account.farm_amounts.get(&farm_id).unwrap_or(&0)),
+                }
+            })
+            .collect();
+        AccountDetails {
+            unstaked_balance: account.unstaked.into(),
+            staked_balance: self
+                .staked_amount_from_num_shares_rounded_down(account.stake_shares)
+                .into(),
+            can_withdraw: account.unstaked_available_epoch_height <= env::epoch_height(),
+            unstaked_available_epoch_height: account.unstaked_available_epoch_height.into(),
+            farms,
+            account_id,
+        }
+    }
+
     /// Returns the number of accounts that have positive balance on this staking pool.
     pub fn get_number_of_accounts(&self) -> u64 {
         self.accounts.len()
@@ -214,7 +597,7 @@ impl StakingContract {
     pub fn get_accounts(&self, from_index: u64, limit: u64) -> Vec<HumanReadableAccount> {
         let keys = self.accounts.keys_as_vector();
 
-        (from_index..std::cmp::min(from_index + limit, keys.len()))
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
             .map(|index| self.get_account(keys.get(index).unwrap()))
             .collect()
     }
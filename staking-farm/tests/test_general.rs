@@ -10,6 +10,7 @@ use near_sdk_sim::{
 use near_sdk_sim::num_rational::Rational;
 use staking_farm::{
     HumanReadableAccount, HumanReadableFarm, PoolSummary, Ratio, StakingContractContract,
+    GAS_PER_FARM_DISTRIBUTION, MAX_NUM_ACTIVE_FARMS,
 };
 
 type PoolContract = ContractAccount<StakingContractContract>;
@@ -169,7 +170,7 @@ fn setup(
         signer_account: root,
         // adding STAKE_SHARE_PRICE_GUARANTEE_FUND to remove this rounding issue from further calculations.
         deposit: pool_initial_balance,
-        init_method: new(root.account_id(), STAKING_KEY.parse().unwrap(), reward_ratio, burn_ratio)
+        init_method: new(root.account_id(), STAKING_KEY.parse().unwrap(), reward_ratio, burn_ratio, None, None, None, None)
     );
     assert_all_success(root.call(
         token_id(),
@@ -310,6 +311,124 @@ fn test_farm() {
     // assert_eq!(active_farms.len(), 0);
 }
 
+/// Claiming twice pays out only once.
+#[test]
+fn test_farm_double_claim() {
+    let (root, pool) = setup(to_yocto("10000") + 1_000_000_000_000, 0, 0);
+    let user1 = create_user_and_stake(&root, &pool);
+    deploy_farm(&root);
+    produce_blocks(&root, 10);
+    let unclaimed = to_int(view!(pool.get_unclaimed_reward(user1.account_id(), 0)));
+    assert!(unclaimed > 0);
+
+    assert_all_success(call!(user1, pool.claim(token_id(), None), deposit = 1));
+    assert!(!call!(user1, pool.claim(token_id(), None), deposit = 1).is_ok());
+    assert_eq!(balance_of(&root, user1.account_id()), unclaimed);
+}
+
+/// Claim without enough gas fails up front and leaves the reward in the pool.
+#[test]
+fn test_farm_claim_not_enough_gas() {
+    let (root, pool) = setup(to_yocto("10000") + 1_000_000_000_000, 0, 0);
+    let user1 = create_user_and_stake(&root, &pool);
+    deploy_farm(&root);
+    produce_blocks(&root, 10);
+    let unclaimed = to_int(view!(pool.get_unclaimed_reward(user1.account_id(), 0)));
+    assert!(unclaimed > 0);
+
+    assert!(!user1
+        .call(
+            pool.account_id(),
+            "claim",
+            &serde_json::to_vec(&json!({ "token_id": token_id() })).unwrap(),
+            1_000_000_000_000,
+            1,
+        )
+        .is_ok());
+    assert_eq!(balance_of(&root, user1.account_id()), 0);
+    assert!(to_int(view!(pool.get_unclaimed_reward(user1.account_id(), 0))) >= unclaimed);
+}
+
+/// Measures the gas of distributing a running farm to an account, by comparing the same
+/// `deposit_and_stake` without farms and with the maximum number of active farms.
+#[test]
+fn test_farm_distribution_gas() {
+    let (root, pool) = setup(to_yocto("10000") + 1_000_000_000_000, 0, 0);
+    let user1 = create_user_and_stake(&root, &pool);
+    let gas_without_farms = u64::from(
+        call!(user1, pool.deposit_and_stake(), deposit = to_yocto("1")).gas_burnt(),
+    );
+
+    let num_farms = 3;
+    let start_date = root.borrow_runtime().cur_block.block_timestamp + ONE_SEC_IN_NS * 3;
+    for i in 0..num_farms {
+        let msg = serde_json::to_string(&json!({
+            "name": format!("Test{}", i),
+            "start_date": format!("{}", start_date),
+            "end_date": format!("{}", start_date + ONE_SEC_IN_NS * 1000),
+        }))
+        .unwrap();
+        assert_all_success(root.call(
+            token_id(),
+            "ft_transfer_call",
+            &serde_json::to_vec(&json!({
+                "receiver_id": STAKING_POOL_ACCOUNT_ID,
+                "amount": to_yocto("1000").to_string(),
+                "msg": msg,
+            }))
+            .unwrap(),
+            near_sdk_sim::DEFAULT_GAS,
+            1,
+        ));
+    }
+    produce_blocks(&root, 5);
+    // Distribute the rewards accumulated before, so both calls distribute a few sessions.
+    assert_all_success(call!(user1, pool.deposit_and_stake(), deposit = to_yocto("1")));
+    produce_blocks(&root, 1);
+    let gas_with_farms = u64::from(
+        call!(user1, pool.deposit_and_stake(), deposit = to_yocto("1")).gas_burnt(),
+    );
+
+    let gas_per_farm = (gas_with_farms - gas_without_farms) / num_farms;
+    println!("Gas per farm distribution: {}", gas_per_farm);
+    assert!(
+        gas_per_farm <= GAS_PER_FARM_DISTRIBUTION.0 / 2,
+        "Gas per farm distribution {} is close to the bound {}",
+        gas_per_farm,
+        GAS_PER_FARM_DISTRIBUTION.0
+    );
+}
+
+/// Not authorized user can't top up an existing farm and gets the tokens back.
+#[test]
+fn test_farm_top_up_not_authorized_user() {
+    let (root, pool) = setup(to_yocto("10000") + 1_000_000_000_000, 0, 0);
+    let user1 = create_user_and_stake(&root, &pool);
+    deploy_farm(&root);
+    assert_all_success(root.call(
+        token_id(),
+        "mint",
+        &serde_json::to_vec(&json!({ "account_id": user1.account_id(), "amount": to_yocto("10000").to_string() })).unwrap(),
+        near_sdk_sim::DEFAULT_GAS,
+        0,
+    ));
+
+    let end_date = root.borrow_runtime().cur_block.block_timestamp + ONE_SEC_IN_NS * 100;
+    let msg =
+        serde_json::to_string(&json!({ "end_date": end_date.to_string(), "farm_id": 0 })).unwrap();
+    assert_some_fail(user1.call(
+        token_id(),
+        "ft_transfer_call",
+        &serde_json::to_vec(&json!({ "receiver_id": STAKING_POOL_ACCOUNT_ID, "amount": to_yocto("10000").to_string(), "msg": msg })).unwrap(),
+        near_sdk_sim::DEFAULT_GAS,
+        1
+    ));
+
+    assert_eq!(balance_of(&root, user1.account_id()), to_yocto("10000"));
+    let farm = view!(pool.get_farm(0)).unwrap_json::<HumanReadableFarm>();
+    assert_eq!(farm.amount.0, to_yocto("50000"));
+}
+
 /// Tests pool, depositing from regular account and lockup.
 /// Creating two farms, farming from them, claiming via delegated call.
 /// Additionally checks that 30% of rewards are burnt (sent 0x0)
@@ -494,8 +613,8 @@ fn test_farm_with_lockup() {
 }
 
 #[test]
-fn test_all_rewards_no_burn() {
-    let (root, pool) = setup(to_yocto("5"), 10, 0);
+fn test_max_reward_fee_no_burn() {
+    let (root, pool) = setup(to_yocto("5"), 5, 0);
     assert_eq!(
         to_int(view!(pool.get_account_total_balance(root.account_id()))),
         to_yocto("0")
@@ -503,29 +622,33 @@ fn test_all_rewards_no_burn() {
     let user1 = create_user_and_stake(&root, &pool);
     wait_epoch(&root);
     assert_all_success(call!(root, pool.ping()));
-    assert_eq!(
+    assert_between(
         to_int(view!(pool.get_account_total_balance(root.account_id()))),
-        to_yocto("1000")
+        "499",
+        "501",
     );
-    assert_eq!(
+    assert_between(
         to_int(view!(pool.get_account_total_balance(user1.account_id()))),
-        to_yocto("10000")
+        "10499",
+        "10501",
     );
 }
 
 #[test]
-fn test_all_rewards_burn() {
-    let (root, pool) = setup(to_yocto("5"), 10, 1);
+fn test_max_reward_fee_burn() {
+    let (root, pool) = setup(to_yocto("5"), 5, 1);
     let user1 = create_user_and_stake(&root, &pool);
     wait_epoch(&root);
     assert_all_success(call!(root, pool.ping()));
-    assert_eq!(
+    assert_between(
         to_int(view!(pool.get_account_total_balance(root.account_id()))),
-        to_yocto("900")
+        "449",
+        "451",
     );
-    assert_eq!(
+    assert_between(
         to_int(view!(pool.get_account_total_balance(user1.account_id()))),
-        to_yocto("10000")
+        "10449",
+        "10451",
     );
 }
 
@@ -957,6 +1080,41 @@ fn test_farm_change_errors() {
     );
 }
 
+/// Stopping the farm when the owner is not registered with the token keeps the funds in the farm.
+#[test]
+fn test_stop_farm_owner_not_registered() {
+    let (root, pool) = setup(to_yocto("5"), 0, 0);
+    let user1 = create_user_and_stake(&root, &pool);
+    deploy_farm(&root);
+
+    let new_owner = root.create_user(
+        AccountId::new_unchecked("new_owner".to_string()),
+        to_yocto("100"),
+    );
+    call(
+        &root,
+        pool.account_id(),
+        "set_owner_id",
+        json!({ "owner_id": new_owner.account_id() }),
+        0,
+    );
+
+    // Transfer of the leftover to the new owner fails.
+    assert_some_fail(call!(new_owner, pool.stop_farm(0)));
+    assert_eq!(balance_of(&root, new_owner.account_id()), 0);
+
+    let farm = view!(pool.get_farm(0)).unwrap_json::<HumanReadableFarm>();
+    assert!(farm.active);
+    assert_eq!(farm.amount.0, to_yocto("50000"));
+
+    produce_blocks(&root, 10);
+    assert_between(
+        to_int(view!(pool.get_unclaimed_reward(user1.account_id(), 0))),
+        "49000",
+        "50000",
+    );
+}
+
 #[test]
 fn test_burn_fee() {
     let (root, pool) = setup(to_yocto("5"), 1, 3);
@@ -996,3 +1154,44 @@ fn test_burn_fee() {
     assert_eq!(pool_summary.burn_fee_fraction.numerator, 0);
     assert_eq!(pool_summary.burn_fee_fraction.denominator, 1);
 }
+
+#[test]
+fn test_farm_active_cap_refund() {
+    let (root, pool) = setup(to_yocto("5"), 0, 0);
+    let _user1 = create_user_and_stake(&root, &pool);
+
+    let ft_transfer_call_farm = |name: String| {
+        let start_date = root.borrow_runtime().cur_block.block_timestamp + ONE_SEC_IN_NS * 3;
+        let end_date = start_date + ONE_SEC_IN_NS * 5;
+        let msg = serde_json::to_string(&json!({ "name": name, "start_date": format!("{}", start_date), "end_date": format!("{}", end_date) }))
+            .unwrap();
+        root.call(
+            token_id(),
+            "ft_transfer_call",
+            &serde_json::to_vec(&json!({ "receiver_id": STAKING_POOL_ACCOUNT_ID, "amount": to_yocto("50000").to_string(), "msg": msg })).unwrap(),
+            near_sdk_sim::DEFAULT_GAS,
+            1,
+        )
+    };
+    let num_farms = MAX_NUM_ACTIVE_FARMS;
+    for i in 0..num_farms {
+        assert_all_success(ft_transfer_call_farm(format!("Test{}", i)));
+    }
+    assert_eq!(
+        view!(pool.get_active_farms())
+            .unwrap_json::<Vec<HumanReadableFarm>>()
+            .len(),
+        num_farms
+    );
+
+    // Tokens are returned to the sender when the cap is exceeded.
+    let balance = balance_of(&root, root.account_id());
+    assert_some_fail(ft_transfer_call_farm(format!("Test{}", num_farms)));
+    assert_eq!(balance_of(&root, root.account_id()), balance);
+    assert_eq!(
+        view!(pool.get_active_farms())
+            .unwrap_json::<Vec<HumanReadableFarm>>()
+            .len(),
+        num_farms
+    );
+}